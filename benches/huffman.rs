@@ -0,0 +1,183 @@
+// Compares a bit-by-bit walk of a Huffman tree, the way the decoder used to
+// read symbols, against the lookup tables of `HuffmanTree::decode`. Run with
+// `cargo bench`.
+use rpeg::bit_reader::BitReader;
+use rpeg::encoder::{AC_LUMINANCE_LENGTHS as AC_LENGTHS, AC_LUMINANCE_VALUES as AC_VALUES};
+use rpeg::huffman_tree::{HuffmanResult, HuffmanTree};
+use std::time::Instant;
+
+const SYMBOL_COUNT: usize = 200_000;
+const ROUNDS: usize = 5;
+
+// Canonical (code, length) of every symbol in `AC_VALUES`
+fn canonical_codes() -> Vec<(u16, u8)> {
+    let mut codes = Vec::new();
+    let mut code = 0u16;
+    for (i, l) in AC_LENGTHS.iter().enumerate() {
+        for _ in 0..*l {
+            codes.push((code, i as u8 + 1));
+            code += 1;
+        }
+        code <<= 1;
+    }
+    codes
+}
+
+// Picks symbols with a simple LCG, favouring the short codes the way real
+// images do, and returns their bits both packed (with stuffed zeros) and unpacked
+fn encode_symbols() -> (Vec<u8>, Vec<bool>, Vec<u8>) {
+    let codes = canonical_codes();
+    let mut seed: u32 = 0x1234_5678;
+    let mut bits = Vec::new();
+    let mut symbols = Vec::with_capacity(SYMBOL_COUNT);
+
+    for _ in 0..SYMBOL_COUNT {
+        seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+        let r = (seed >> 16) as usize;
+        let index = if r.is_multiple_of(4) {
+            r % codes.len()
+        } else {
+            r % 16
+        };
+        let (code, length) = codes[index];
+        for i in (0..length).rev() {
+            bits.push((code >> i) & 1 == 1);
+        }
+        symbols.push(AC_VALUES[index]);
+    }
+
+    let mut bytes = Vec::new();
+    for chunk in bits.chunks(8) {
+        let mut byte = 0u8;
+        for (i, bit) in chunk.iter().enumerate() {
+            byte |= (*bit as u8) << (7 - i);
+        }
+        // pad the last byte with ones, as encoders do
+        byte |= 0xFFu8.checked_shr(chunk.len() as u32).unwrap_or(0);
+        bytes.push(byte);
+        if byte == 0xFF {
+            bytes.push(0x00);
+        }
+    }
+
+    (bytes, bits, symbols)
+}
+
+// The tree walk baseline: the tree is walked from its root again for every bit
+// added to the code, until the code reaches a leaf
+struct TreeWalk {
+    children: Vec<[Option<usize>; 2]>, // (left, right) of every node, the root first
+    symbols: Vec<Option<u8>>,
+}
+
+enum Walk {
+    Symbol(u8),
+    Prefix, // a prefix of a longer code
+    Invalid,
+}
+
+impl TreeWalk {
+    fn new(codes: &[(u16, u8)], values: &[u8]) -> Self {
+        let mut tree = Self {
+            children: vec![[None, None]],
+            symbols: vec![None],
+        };
+        for ((code, length), value) in codes.iter().zip(values.iter()) {
+            let mut node = 0;
+            for i in (0..*length).rev() {
+                let bit = ((code >> i) & 1) as usize;
+                node = match tree.children[node][bit] {
+                    Some(child) => child,
+                    None => {
+                        tree.children.push([None, None]);
+                        tree.symbols.push(None);
+                        tree.children[node][bit] = Some(tree.children.len() - 1);
+                        tree.children.len() - 1
+                    }
+                };
+            }
+            tree.symbols[node] = Some(*value);
+        }
+
+        tree
+    }
+
+    fn try_decode(&self, bits: &[bool]) -> Walk {
+        let mut node = 0;
+        for bit in bits.iter() {
+            node = match self.children[node][*bit as usize] {
+                Some(child) => child,
+                None => return Walk::Invalid,
+            };
+        }
+
+        match self.symbols[node] {
+            Some(symbol) => Walk::Symbol(symbol),
+            None => Walk::Prefix,
+        }
+    }
+}
+
+fn result_to_symbol(result: HuffmanResult) -> u8 {
+    match result {
+        HuffmanResult::Some(symbol) => symbol,
+        _ => panic!("[E] - invalid huffman code"),
+    }
+}
+
+fn decode_with_tree(tree: &TreeWalk, bits: &[bool]) -> Vec<u8> {
+    let mut symbols = Vec::with_capacity(SYMBOL_COUNT);
+    let mut code = Vec::new();
+    for bit in bits.iter() {
+        code.push(*bit);
+        match tree.try_decode(&code) {
+            Walk::Prefix => continue,
+            Walk::Symbol(symbol) => symbols.push(symbol),
+            Walk::Invalid => panic!("[E] - invalid huffman code"),
+        }
+        code.clear();
+    }
+    symbols
+}
+
+fn decode_with_lookup(tree: &HuffmanTree, bytes: &[u8]) -> Vec<u8> {
+    let mut reader = BitReader::new(bytes, 0);
+    (0..SYMBOL_COUNT)
+        .map(|_| result_to_symbol(tree.decode(&mut reader).expect("[E] - truncated data")))
+        .collect()
+}
+
+fn bench<F: FnMut() -> Vec<u8>>(name: &str, expected: &[u8], mut decode: F) {
+    let mut best = None;
+    for _ in 0..ROUNDS {
+        let start = Instant::now();
+        let symbols = decode();
+        let elapsed = start.elapsed();
+        assert_eq!(symbols, expected, "{} decoded the wrong symbols", name);
+        best = Some(best.map_or(elapsed, |best: std::time::Duration| best.min(elapsed)));
+    }
+
+    let best = best.unwrap();
+    println!(
+        "{:<8} {:>10.3} ms ({:.1} ns per symbol)",
+        name,
+        best.as_secs_f64() * 1000.0,
+        best.as_nanos() as f64 / SYMBOL_COUNT as f64
+    );
+}
+
+fn main() {
+    let mut tree = HuffmanTree::new(1, 0);
+    tree.build(&AC_LENGTHS, &AC_VALUES)
+        .expect("[E] - invalid huffman table");
+
+    let tree_walk = TreeWalk::new(&canonical_codes(), &AC_VALUES);
+    let (bytes, bits, symbols) = encode_symbols();
+
+    println!(
+        "decoding {} symbols, best of {} rounds",
+        SYMBOL_COUNT, ROUNDS
+    );
+    bench("tree", &symbols, || decode_with_tree(&tree_walk, &bits));
+    bench("lookup", &symbols, || decode_with_lookup(&tree, &bytes));
+}
@@ -0,0 +1,119 @@
+use crate::codes_markers::*;
+use crate::error::{DecodeError, DecodeErrorKind};
+
+// Reads the entropy-coded data of a scan, removing the stuffed zeros on the fly.
+// Bits are kept left aligned in a 64-bit buffer, so up to 16 of them can be
+// peeked before deciding how many to consume.
+pub struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    base: usize, // offset of `data` inside the whole image
+
+    bit_buffer: u64,
+    bit_count: u32,
+
+    marker: Option<u8>, // marker the reader stopped at, if any
+}
+
+impl<'a> BitReader<'a> {
+    pub fn new(data: &'a [u8], base: usize) -> Self {
+        Self {
+            data,
+            pos: 0,
+            base,
+            bit_buffer: 0,
+            bit_count: 0,
+            marker: None,
+        }
+    }
+
+    // Offset (in the whole image) of the byte holding the next unread bit
+    pub fn offset(&self) -> usize {
+        (self.base + self.pos).saturating_sub(self.bit_count as usize / 8)
+    }
+
+    pub fn error(&self, kind: DecodeErrorKind) -> DecodeError {
+        DecodeError::new(kind, self.offset())
+    }
+
+    fn fill(&mut self) {
+        while self.bit_count <= 56 && self.marker.is_none() && self.pos < self.data.len() {
+            let byte = self.data[self.pos];
+            if byte == JFIF_BYTE_FF {
+                match self.data.get(self.pos + 1) {
+                    Some(0x00) => self.pos += 1,
+                    Some(marker) => {
+                        self.marker = Some(*marker);
+                        break;
+                    }
+                    None => (),
+                }
+            }
+            self.pos += 1;
+
+            self.bit_buffer |= (byte as u64) << (56 - self.bit_count);
+            self.bit_count += 8;
+        }
+    }
+
+    // Returns the next `count` (at most 16) bits without consuming them, padded
+    // with zeros past the end of the data
+    pub fn peek_bits(&mut self, count: u8) -> u16 {
+        if count == 0 {
+            return 0;
+        }
+        if self.bit_count < count as u32 {
+            self.fill();
+        }
+
+        (self.bit_buffer >> (64 - count as u32)) as u16
+    }
+
+    pub fn consume_bits(&mut self, count: u8) -> Result<(), DecodeError> {
+        if self.bit_count < count as u32 {
+            self.fill();
+            if self.bit_count < count as u32 {
+                return Err(self.error(DecodeErrorKind::Truncated));
+            }
+        }
+        self.bit_buffer = self.bit_buffer.checked_shl(count as u32).unwrap_or(0);
+        self.bit_count -= count as u32;
+
+        Ok(())
+    }
+
+    pub fn next_bits(&mut self, count: u8) -> Result<u16, DecodeError> {
+        let bits = self.peek_bits(count);
+        self.consume_bits(count)?;
+
+        Ok(bits)
+    }
+
+    pub fn next_bit(&mut self) -> Result<bool, DecodeError> {
+        Ok(self.next_bits(1)? == 1)
+    }
+
+    // Drops the padding bits of the current interval and reads the RSTn marker
+    // ending it, checking that it is the `restart_count`-th one
+    pub fn restart(&mut self, restart_count: usize) -> Result<(), DecodeError> {
+        self.bit_buffer = 0;
+        self.bit_count = 0;
+        while self.marker.is_none() && self.pos < self.data.len() {
+            self.fill();
+            self.bit_buffer = 0;
+            self.bit_count = 0;
+        }
+
+        let marker = self
+            .marker
+            .ok_or_else(|| self.error(DecodeErrorKind::InvalidSegment("restart marker missing")))?;
+        if marker != JFIF_RST0 + (restart_count % 8) as u8 {
+            return Err(self.error(DecodeErrorKind::BadMarker(marker)));
+        }
+
+        self.pos += 2;
+        self.marker = None;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,67 @@
+use crate::codes_markers::*;
+
+// Writes the entropy-coded data of a scan, stuffing a zero after every 0xFF byte
+pub struct BitWriter {
+    bytes: Vec<u8>,
+
+    bit_buffer: u32, // pending bits, right aligned
+    bit_count: u32,
+}
+
+impl BitWriter {
+    pub fn new() -> Self {
+        Self {
+            bytes: Vec::new(),
+            bit_buffer: 0,
+            bit_count: 0,
+        }
+    }
+
+    // Appends the `count` (at most 16) low bits of `bits`, most significant first
+    pub fn write_bits(&mut self, bits: u16, count: u8) {
+        if count == 0 {
+            return;
+        }
+        let mask = (1u32 << count) - 1;
+        self.bit_buffer = (self.bit_buffer << count) | (bits as u32 & mask);
+        self.bit_count += count as u32;
+
+        while self.bit_count >= 8 {
+            self.bit_count -= 8;
+            self.push_byte((self.bit_buffer >> self.bit_count) as u8);
+        }
+        self.bit_buffer &= (1 << self.bit_count) - 1;
+    }
+
+    // Pads the last byte with ones, so the padding never looks like a code prefix of zeros
+    pub fn flush(&mut self) {
+        if self.bit_count > 0 {
+            let padding = 8 - self.bit_count as u8;
+            self.write_bits(0xFF, padding);
+        }
+    }
+
+    // Byte aligns the data and appends a marker, such as RSTn, without stuffing it
+    pub fn write_marker(&mut self, marker: u8) {
+        self.flush();
+        self.bytes.extend_from_slice(&[JFIF_BYTE_FF, marker]);
+    }
+
+    pub fn into_bytes(mut self) -> Vec<u8> {
+        self.flush();
+        self.bytes
+    }
+
+    fn push_byte(&mut self, byte: u8) {
+        self.bytes.push(byte);
+        if byte == JFIF_BYTE_FF {
+            self.bytes.push(0x00);
+        }
+    }
+}
+
+impl Default for BitWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,121 @@
+use crate::image::{Image, PixelFormat};
+use std::io;
+use std::io::Write;
+
+const FILE_HEADER_SIZE: usize = 14;
+const INFO_HEADER_SIZE: usize = 40; // BITMAPINFOHEADER
+
+// Writes the image as an uncompressed BMP, with 32 bits per pixel (the fourth
+// byte holding the alpha sample) for RGBA and BGRA images and 24 bits otherwise
+pub fn write_bmp<W: Write>(img: &Image, writer: &mut W) -> io::Result<()> {
+    let bytes_per_pixel = match img.format() {
+        PixelFormat::Rgba8 | PixelFormat::Bgra8 => 4,
+        _ => 3,
+    };
+    // rows are padded to a multiple of 4 bytes
+    let row_size = (img.width() * bytes_per_pixel).div_ceil(4) * 4;
+    let data_offset = FILE_HEADER_SIZE + INFO_HEADER_SIZE;
+    let file_size = row_size
+        .checked_mul(img.height())
+        .and_then(|size| size.checked_add(data_offset))
+        .filter(|size| *size <= u32::MAX as usize && img.width() <= i32::MAX as usize)
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "image is too large to fit a BMP file",
+            )
+        })?;
+
+    writer.write_all(b"BM")?;
+    writer.write_all(&(file_size as u32).to_le_bytes())?;
+    writer.write_all(&[0; 4])?;
+    writer.write_all(&(data_offset as u32).to_le_bytes())?;
+
+    writer.write_all(&(INFO_HEADER_SIZE as u32).to_le_bytes())?;
+    writer.write_all(&(img.width() as i32).to_le_bytes())?;
+    // a positive height stores the rows from bottom to top
+    writer.write_all(&(img.height() as i32).to_le_bytes())?;
+    writer.write_all(&1u16.to_le_bytes())?;
+    writer.write_all(&(bytes_per_pixel as u16 * 8).to_le_bytes())?;
+    // BI_RGB, then the image size, 72 DPI and no palette
+    writer.write_all(&0u32.to_le_bytes())?;
+    writer.write_all(&((file_size - data_offset) as u32).to_le_bytes())?;
+    writer.write_all(&2835i32.to_le_bytes())?;
+    writer.write_all(&2835i32.to_le_bytes())?;
+    writer.write_all(&[0; 8])?;
+
+    let mut row = vec![0; row_size];
+    for y in (0..img.height()).rev() {
+        for x in 0..img.width() {
+            let [r, g, b] = img.get_rgb(x, y).unwrap_or_default();
+            let offset = x * bytes_per_pixel;
+            row[offset..offset + 3].copy_from_slice(&[b, g, r]);
+            if bytes_per_pixel == 4 {
+                row[offset + 3] = img.get_pixel(x, y).unwrap_or_default()[3];
+            }
+        }
+        writer.write_all(&row)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn le_u32(bytes: &[u8]) -> u32 {
+        u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+    }
+
+    fn test_image(width: usize, height: usize, format: PixelFormat) -> Image {
+        let data = (0..format.buffer_size(width, height))
+            .map(|i| (i * 37 % 256) as u8)
+            .collect();
+
+        Image::from_vec(width, height, format, data).unwrap()
+    }
+
+    #[test]
+    fn pads_rows_and_stores_them_bottom_up() {
+        // 3, 6, 9, 12 and 15 bytes of pixels
+        for (width, row_size) in [(1, 4), (2, 8), (3, 12), (4, 12), (5, 16)].iter() {
+            let (width, row_size) = (*width, *row_size);
+            let img = test_image(width, 3, PixelFormat::Rgb8);
+            let mut bytes = Vec::new();
+            write_bmp(&img, &mut bytes).unwrap();
+
+            let data_offset = FILE_HEADER_SIZE + INFO_HEADER_SIZE;
+            assert_eq!(&bytes[..2], b"BM");
+            assert_eq!(le_u32(&bytes[2..]) as usize, bytes.len());
+            assert_eq!(bytes.len(), data_offset + row_size * 3);
+            assert_eq!(le_u32(&bytes[10..]) as usize, data_offset);
+            assert_eq!(le_u32(&bytes[18..]) as usize, width);
+            assert_eq!(le_u32(&bytes[22..]), 3);
+            assert_eq!(bytes[28], 24);
+
+            for y in 0..3 {
+                let row = &bytes[data_offset + (2 - y) * row_size..][..row_size];
+                for x in 0..width {
+                    let [r, g, b] = img.get_rgb(x, y).unwrap();
+                    assert_eq!(row[x * 3..x * 3 + 3], [b, g, r]);
+                }
+                assert!(row[width * 3..].iter().all(|byte| *byte == 0));
+            }
+        }
+    }
+
+    #[test]
+    fn keeps_the_alpha_of_32_bit_files() {
+        let img = test_image(3, 2, PixelFormat::Rgba8);
+        let mut bytes = Vec::new();
+        write_bmp(&img, &mut bytes).unwrap();
+
+        let data_offset = FILE_HEADER_SIZE + INFO_HEADER_SIZE;
+        assert_eq!(bytes[28], 32);
+        assert_eq!(bytes.len(), data_offset + 3 * 4 * 2);
+        // the last row comes first
+        let [r, g, b, a] = img.get_pixel(2, 1).unwrap();
+        assert_eq!(bytes[data_offset + 8..data_offset + 12], [b, g, r, a]);
+    }
+}
@@ -13,15 +13,22 @@ pub const JFIF_SOF11: u8 = 0xCB; // Lossless (Sequential), Arithmetic Coding
 pub const JFIF_SOF13: u8 = 0xCD; // Differential Sequential DCT, Arithmetic Coding
 pub const JFIF_SOF14: u8 = 0xCE; // Differential Progressive DCT, Arithmetic Coding
 pub const JFIF_SOF15: u8 = 0xCF; // Differential Lossless (Sequential), Arithmetic Coding
+pub const JFIF_RST0: u8 = 0xD0; // Restart Marker 0, followed by RST1 to RST7 in a cycle
+pub const JFIF_RST7: u8 = 0xD7; // Restart Marker 7
 pub const JFIF_SOI: u8 = 0xD8; // Start of Image
 pub const JFIF_EOI: u8 = 0xD9; // End of Image
 pub const JFIF_SOS: u8 = 0xDA; // Start of Scan
 pub const JFIF_DQT: u8 = 0xDB; // Define Quantization Table
+pub const JFIF_DRI: u8 = 0xDD; // Define Restart Interval
 pub const JFIF_APP0: u8 = 0xE0; // Application Segment 0, JPEG-JFIF Image
 pub const JFIF_COM: u8 = 0xFE; // Comment
 
 pub const HUFFMAN_DC: u8 = 0; // DC value for DHT
 pub const HUFFMAN_AC: u8 = 1; // AC value for DHT
 
+pub const HUFFMAN_EOB: u8 = 0x00; // AC symbol ending the block (run 0, category 0)
+pub const HUFFMAN_ZRL: u8 = 0xF0; // AC symbol for a run of 16 zeros (run 15, category 0)
+pub const HUFFMAN_MAX_DC_CATEGORY: u8 = 11; // Largest DC difference category of 8-bit samples
+
 pub const CHANNEL_LUMA: u8 = 0; // Code for luminance channel
 pub const CHANNEL_CHROMA: u8 = 1; // Code for chrominance channel
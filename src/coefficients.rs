@@ -0,0 +1,50 @@
+use crate::component::Component;
+use crate::mcu::ZIGZAG;
+
+// Order of the 64 values of every block and quantization table
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CoefficientOrder {
+    Zigzag,  // as stored in the file
+    Natural, // row by row, lowest frequencies first
+}
+
+// Quantized DCT coefficients of a decoded frame, as returned by `Decoder::decode_coefficients`
+pub struct Coefficients {
+    pub width: usize, // in pixels
+    pub height: usize,
+    pub order: CoefficientOrder,
+    pub components: Vec<ComponentCoefficients>,
+}
+
+// Blocks of a component, row by row. The grid is padded to whole MCUs, so its
+// last columns and rows may lie past the edge of the image.
+pub struct ComponentCoefficients {
+    pub component: Component,
+    pub width_in_blocks: usize,
+    pub height_in_blocks: usize,
+    pub quantization_table: [u16; 64],
+    pub blocks: Vec<[i32; 64]>,
+}
+
+impl ComponentCoefficients {
+    pub fn block(&self, x: usize, y: usize) -> Option<&[i32; 64]> {
+        if x >= self.width_in_blocks {
+            return None;
+        }
+        self.blocks.get(y * self.width_in_blocks + x)
+    }
+}
+
+// Reorders 64 zigzag ordered values, leaving them as they are for `Zigzag`
+pub fn reorder<T: Copy + Default>(zz_values: &[T; 64], order: CoefficientOrder) -> [T; 64] {
+    match order {
+        CoefficientOrder::Zigzag => *zz_values,
+        CoefficientOrder::Natural => {
+            let mut values = [T::default(); 64];
+            for (val, zz_index) in values.iter_mut().zip(ZIGZAG.iter().flatten()) {
+                *val = zz_values[*zz_index];
+            }
+            values
+        }
+    }
+}
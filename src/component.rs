@@ -0,0 +1,14 @@
+// A component (channel) of the frame, as declared in the SOF segment
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Component {
+    pub id: u8,
+    pub horizontal_sampling: usize,
+    pub vertical_sampling: usize,
+    pub quantization_table: u8,
+}
+
+impl Component {
+    pub fn blocks_per_mcu(&self) -> usize {
+        self.horizontal_sampling * self.vertical_sampling
+    }
+}
@@ -1,46 +1,185 @@
+use crate::bit_reader::BitReader;
 use crate::codes_markers::*;
+use crate::coefficients::{reorder, CoefficientOrder, Coefficients, ComponentCoefficients};
+use crate::component::Component;
+use crate::error::{DecodeError, DecodeErrorKind};
 use crate::huffman_tree::{HuffmanResult, HuffmanTree};
-use crate::image::Image;
+use crate::idct::{IdctMethod, Scale};
+use crate::image::{Image, PixelFormat};
 use crate::mcu::MCU;
+use crate::segment::Segment;
 use std::collections::HashMap;
 use std::fs;
+use std::io::Read;
+use std::ops::Range;
 
-pub struct Decoder<'a> {
-    img_path: &'a str,
+// Frames above 2^27 pixels (a 16384 x 8192 image) are rejected unless the limit
+// is raised, their coefficients alone taking gigabytes
+pub const DEFAULT_MAX_PIXELS: usize = 1 << 27;
 
+pub struct Decoder {
     img_bytes: Vec<u8>,
 
-    huffman_tables: Vec<HuffmanTree>,
+    huffman_tables: [[Option<HuffmanTree>; 4]; 2], // indexed by class (DC, AC) and id
     component_id_to_huffman_table: HashMap<usize, (u8, u8)>,
 
-    quantization_table_luma: [[u8; 8]; 8],
-    quantization_table_chroma: [[u8; 8]; 8],
+    quantization_tables: [Option<[u16; 64]>; 4], // in zigzag order
+    component_quantization: Vec<Option<[u16; 64]>>, // latched at the first scan of a component
 
     img_height: usize,
     img_width: usize,
+    components: Vec<Component>,
+    progressive: bool,
+
+    scan: Scan,
+    restart_interval: usize, // MCUs between RSTn markers, 0 when disabled
+    coefficients: Vec<Vec<[i32; 64]>>, // quantized blocks of every component, row by row
 
     mcus: Vec<MCU>,
+    idct_method: IdctMethod,
+    scale: Scale,
+    pixel_format: Option<PixelFormat>, // Gray8 or RGB8 depending on the components when unset
+    dc_only: bool,                     // stop progressive files once every DC coefficient is known
+    region: Option<(usize, usize, usize, usize)>, // (x, y, width, height) of the pixels to decode
+    streaming: bool, // stop at the first scan when it can be decoded one MCU row at a time
+    streamed_scan: Option<(usize, usize)>, // offset and length of the data of that scan
+    max_pixels: usize, // largest frame whose coefficients are all kept in memory
+    output_size: Option<usize>, // bytes of the buffer given to `decode_into`, checked at the SOF
+
+    segments: Vec<Segment>,
+}
+
+// Decoding state carried from one unit of a scan to the next
+struct ScanState {
+    dc_predictions: Vec<i32>, // one per component of the frame
+    eob_run: u32,
+    skip_interval: bool, // the current restart interval lies outside the region
 }
 
-fn next_byte<T: Iterator<Item = u8>>(it: &mut T) -> u8 {
-    it.next().expect("[E] - image ended unexpectedly").clone()
+impl ScanState {
+    fn new(component_count: usize) -> Self {
+        Self {
+            dc_predictions: vec![0; component_count],
+            eob_run: 0,
+            skip_interval: false,
+        }
+    }
 }
 
-fn next_bit<T: Iterator<Item = bool>>(it: &mut T) -> bool {
-    it.next().expect("[E] - image ended unexpectedly").clone()
+// Parameters of the scan currently being decoded, as found in its SOS segment
+#[derive(Default)]
+struct Scan {
+    components: Vec<usize>, // indices of the scanned components in the frame
+    spectral_start: usize,
+    spectral_end: usize,
+    approximation_high: u8,
+    approximation_low: u8,
 }
 
-fn next_n_bytes<T: Iterator<Item = u8>>(it: &mut T, length: usize) -> Vec<u8> {
-    (0..length).into_iter().map(|_| next_byte(it)).collect()
+struct ByteReader<'b> {
+    bytes: &'b [u8],
+    pos: usize,
+    base: usize, // offset of `bytes` inside the whole image
 }
 
-fn all_bytes<T: Iterator<Item = u8>>(it: &mut T) -> Vec<u8> {
-    let mut res = Vec::new();
-    while let Some(val) = it.next() {
-        res.push(val);
+impl<'b> ByteReader<'b> {
+    fn new(bytes: &'b [u8], base: usize) -> Self {
+        Self {
+            bytes,
+            pos: 0,
+            base,
+        }
     }
 
-    res
+    fn offset(&self) -> usize {
+        self.base + self.pos
+    }
+
+    fn error(&self, kind: DecodeErrorKind) -> DecodeError {
+        DecodeError::new(kind, self.offset())
+    }
+
+    fn next_byte(&mut self) -> Result<u8, DecodeError> {
+        let byte = *self
+            .bytes
+            .get(self.pos)
+            .ok_or_else(|| self.error(DecodeErrorKind::Truncated))?;
+        self.pos += 1;
+
+        Ok(byte)
+    }
+
+    fn next_u16(&mut self) -> Result<u16, DecodeError> {
+        Ok(0x10 * 0x10 * self.next_byte()? as u16 + self.next_byte()? as u16)
+    }
+
+    fn next_n_bytes(&mut self, length: usize) -> Result<&'b [u8], DecodeError> {
+        if self.bytes.len() - self.pos < length {
+            return Err(self.error(DecodeErrorKind::Truncated));
+        }
+        let bytes = &self.bytes[self.pos..self.pos + length];
+        self.pos += length;
+
+        Ok(bytes)
+    }
+
+    // Returns the bytes up to the next marker, skipping stuffed zeros and restart markers
+    fn entropy_coded_data(&mut self) -> &'b [u8] {
+        let start = self.pos;
+        while self.pos + 1 < self.bytes.len() {
+            let next = self.bytes[self.pos + 1];
+            if self.bytes[self.pos] == JFIF_BYTE_FF
+                && next != 0x00
+                && !(JFIF_RST0..=JFIF_RST7).contains(&next)
+            {
+                break;
+            }
+            self.pos += 1;
+        }
+        if self.pos + 1 >= self.bytes.len() {
+            self.pos = self.bytes.len();
+        }
+
+        &self.bytes[start..self.pos]
+    }
+
+    // Reads the length of a marker segment and returns a reader over its contents
+    fn segment(&mut self) -> Result<(u16, ByteReader<'b>), DecodeError> {
+        let segment_length = self.next_u16()?;
+        if segment_length < 2 {
+            return Err(DecodeError::new(
+                DecodeErrorKind::InvalidSegment("segment length is smaller than 2"),
+                self.offset() - 2,
+            ));
+        }
+        let base = self.offset();
+        let seg = self.next_n_bytes(segment_length as usize - 2)?;
+
+        Ok((segment_length, ByteReader::new(seg, base)))
+    }
+}
+
+// Reads bits until they form a code of the table and returns its symbol
+fn next_symbol(reader: &mut BitReader, huffman_table: &HuffmanTree) -> Result<u8, DecodeError> {
+    match huffman_table.decode(reader)? {
+        HuffmanResult::Some(val) => Ok(val),
+        _ => Err(reader.error(DecodeErrorKind::InvalidHuffmanCode)),
+    }
+}
+
+// Reads a DC symbol, the category (bit length) of the difference that follows it
+fn next_dc_category(
+    reader: &mut BitReader,
+    huffman_table: &HuffmanTree,
+) -> Result<u8, DecodeError> {
+    let category = next_symbol(reader, huffman_table)?;
+    if category > HUFFMAN_MAX_DC_CATEGORY {
+        return Err(reader.error(DecodeErrorKind::InvalidSegment(
+            "DC difference category exceeds 11",
+        )));
+    }
+
+    Ok(category)
 }
 
 fn bitstring_to_value(mut val: u16, cat: u8) -> i32 {
@@ -60,426 +199,1771 @@ fn bitstring_to_value(mut val: u16, cat: u8) -> i32 {
     res
 }
 
-fn u8_to_bool(val: u8) -> Vec<bool> {
-    (0..8)
-        .map(|i| {
-            let mask = 1 << i;
-            val & mask != 0
-        })
-        .rev()
-        .collect::<Vec<_>>()
-}
+impl Decoder {
+    pub fn new(img_path: &str) -> Result<Self, DecodeError> {
+        let img_bytes = fs::read(img_path)?;
 
-impl<'a> Decoder<'a> {
-    pub fn new(img_path: &'a str) -> Self {
-        let img_bytes = fs::read(&img_path).expect("[E] - no such file exists");
+        Ok(Self::from_vec(img_bytes))
+    }
+
+    pub fn from_bytes(img_bytes: &[u8]) -> Self {
+        Self::from_vec(img_bytes.to_vec())
+    }
 
+    pub fn from_reader<R: Read>(mut reader: R) -> Result<Self, DecodeError> {
+        let mut img_bytes = Vec::new();
+        reader.read_to_end(&mut img_bytes)?;
+
+        Ok(Self::from_vec(img_bytes))
+    }
+
+    pub fn from_vec(img_bytes: Vec<u8>) -> Self {
         Self {
-            img_path,
             img_bytes,
 
-            huffman_tables: Vec::new(),
+            huffman_tables: Default::default(),
             component_id_to_huffman_table: HashMap::new(),
 
-            quantization_table_luma: [[0; 8]; 8],
-            quantization_table_chroma: [[0; 8]; 8],
+            quantization_tables: [None; 4],
+            component_quantization: Vec::new(),
 
             img_height: 0,
             img_width: 0,
+            components: Vec::new(),
+            progressive: false,
+
+            scan: Scan::default(),
+            restart_interval: 0,
+            coefficients: Vec::new(),
 
             mcus: Vec::new(),
+            idct_method: IdctMethod::default(),
+            scale: Scale::default(),
+            pixel_format: None,
+            dc_only: false,
+            region: None,
+            streaming: false,
+            streamed_scan: None,
+            max_pixels: DEFAULT_MAX_PIXELS,
+            output_size: None,
+
+            segments: Vec::new(),
         }
     }
 
-    pub fn debug(&mut self) {
-        let mut img_iter = self.img_bytes.clone().into_iter();
+    // Selects the IDCT used by the following calls to `decode`
+    pub fn set_idct_method(&mut self, idct_method: IdctMethod) {
+        self.idct_method = idct_method;
+    }
 
-        if !(next_byte(&mut img_iter) == 0xff && next_byte(&mut img_iter) == 0xd8) {
-            println!("[E] - file provided is not a JPG image");
-        }
+    // Selects the size of the images returned by the following calls to
+    // `decode`, shrinking every block with a reduced IDCT
+    pub fn set_scale(&mut self, scale: Scale) {
+        self.scale = scale;
+    }
 
-        while let Some(byte) = img_iter.next() {
-            match byte {
-                0xff => {
-                    let byte_marker = next_byte(&mut img_iter);
-                    match byte_marker {
-                        JFIF_APP0 => {
-                            let segment_length = 0x10 * 0x10 * next_byte(&mut img_iter) as u16
-                                + next_byte(&mut img_iter) as u16;
-                            let seg = next_n_bytes(&mut img_iter, segment_length as usize - 2);
+    // Selects the layout of the images returned by the following calls to
+    // `decode`, instead of Gray8 or RGB8 depending on the components of the frame
+    pub fn set_pixel_format(&mut self, pixel_format: PixelFormat) {
+        self.pixel_format = Some(pixel_format);
+    }
 
-                            println!("Parsing APP-O segment:");
-                            println!("\tsegment length: {}", segment_length);
-                            self.parse_app0(seg);
-                        }
-                        JFIF_DQT => {
-                            let segment_length = 0x10 * 0x10 * next_byte(&mut img_iter) as u16
-                                + next_byte(&mut img_iter) as u16;
-                            let seg = next_n_bytes(&mut img_iter, segment_length as usize - 2);
-
-                            println!("Parsing DQT segment:");
-                            println!("\tsegment length: {}", segment_length);
-                            self.parse_dqt(seg);
-                        }
-                        JFIF_DHT => {
-                            let segment_length = 0x10 * 0x10 * next_byte(&mut img_iter) as u16
-                                + next_byte(&mut img_iter) as u16;
-                            let seg = next_n_bytes(&mut img_iter, segment_length as usize - 2);
-
-                            println!("Parsing DHT segment:");
-                            println!("\tsegment length: {}", segment_length);
-                            self.parse_dht(seg);
-                        }
-                        JFIF_SOF0 => {
-                            let segment_length = 0x10 * 0x10 * next_byte(&mut img_iter) as u16
-                                + next_byte(&mut img_iter) as u16;
-                            let seg = next_n_bytes(&mut img_iter, segment_length as usize - 2);
-
-                            println!("Parsing SOF0 segment:");
-                            println!("\tsegment length: {}", segment_length);
-                            self.parse_sof(seg);
-                        }
-                        JFIF_SOS => {
-                            let segment_length = 0x10 * 0x10 * next_byte(&mut img_iter) as u16
-                                + next_byte(&mut img_iter) as u16;
-                            let seg = next_n_bytes(&mut img_iter, segment_length as usize - 2);
-
-                            println!("Parsing SOS segment:");
-                            println!("\tsegment length: {}", segment_length);
-                            self.parse_sos0(seg);
-
-                            let mut rest_of_file = all_bytes(&mut img_iter);
-                            let mut end_of_img = rest_of_file.len() - 2;
-
-                            for p in (1..rest_of_file.len()).rev() {
-                                if rest_of_file[p] == JFIF_EOI
-                                    && rest_of_file[p - 1] == JFIF_BYTE_FF
-                                {
-                                    end_of_img = p - 1;
-                                    break;
-                                }
-                            }
-
-                            rest_of_file.truncate(end_of_img);
-
-                            let mut cleaned_file = Vec::new();
-                            cleaned_file.push(rest_of_file[0]);
-
-                            for i in 1..rest_of_file.len() {
-                                if !(rest_of_file[i - 1] == 0xff && rest_of_file[i] == 0x00) {
-                                    cleaned_file.push(rest_of_file[i]);
-                                }
-                            }
-
-                            self.parse_image_data(cleaned_file);
-                        }
-                        JFIF_COM => println!("\nComment marker:"),
-                        JFIF_EOI => println!("\nEnd of image"),
-                        _ => println!("\nUnknown segment ({:02X})", byte_marker),
-                    }
+    // Limits the frames decoded by the following calls to `decode` to
+    // `max_pixels`, rejecting larger ones before buffering their coefficients
+    pub fn set_max_pixels(&mut self, max_pixels: usize) {
+        self.max_pixels = max_pixels;
+    }
+
+    // Decodes the whole image, without printing anything or touching the disk
+    pub fn decode(&mut self) -> Result<Image, DecodeError> {
+        self.decode_image(self.scale, false)
+    }
+
+    // Decodes a rough image an eighth of the size of the original one from the
+    // DC coefficients alone, one pixel per block. The AC coefficients of
+    // sequential files still have to be parsed, while progressive files are
+    // read only up to their first DC scans.
+    pub fn preview(&mut self) -> Result<Image, DecodeError> {
+        self.decode_image(Scale::Eighth, true)
+    }
+
+    // Decodes the `width` x `height` region at (`x`, `y`), clipped to the image.
+    // Only the MCUs overlapping it go through the IDCT and the color conversion,
+    // scans stop after the last of them and skip the restart intervals lying
+    // entirely outside of it.
+    pub fn decode_region(
+        &mut self,
+        x: usize,
+        y: usize,
+        width: usize,
+        height: usize,
+    ) -> Result<Image, DecodeError> {
+        self.region = Some((x, y, width, height));
+        let res = self.decode_image(Scale::Full, false);
+        self.region = None;
+
+        res
+    }
+
+    fn decode_image(&mut self, scale: Scale, dc_only: bool) -> Result<Image, DecodeError> {
+        self.dc_only = dc_only;
+        let res = self.run(true);
+        self.dc_only = false;
+        res?;
+        if self.components.is_empty() {
+            return Err(DecodeError::new(
+                DecodeErrorKind::MissingFrame,
+                self.img_bytes.len(),
+            ));
+        }
+        let (left, top, right, bottom) = match self.region {
+            Some((x, y, width, height)) => {
+                let right = x.saturating_add(width).min(self.img_width);
+                let bottom = y.saturating_add(height).min(self.img_height);
+                if width == 0 || height == 0 || right <= x || bottom <= y {
+                    return Err(DecodeError::new(
+                        DecodeErrorKind::InvalidRegion,
+                        self.img_bytes.len(),
+                    ));
                 }
-                _ => (),
+                (x, y, right, bottom)
             }
+            None => (0, 0, self.img_width, self.img_height),
+        };
+        // no scan at all, every coefficient stays zero
+        self.allocate_coefficients(self.img_bytes.len())?;
+        self.build_mcus(scale)?;
+
+        // the MCUs cover whole blocks around the region
+        let (mcu_width, mcu_height) = self.mcu_size();
+        let (columns, rows) = self.mcu_window();
+        let x_0 = columns.start * mcu_width;
+        let y_0 = rows.start * mcu_height;
+        let width = scale.scaled_size((columns.end * mcu_width).min(self.img_width) - x_0);
+        let height = scale.scaled_size((rows.end * mcu_height).min(self.img_height) - y_0);
+        let mut img = self.new_image(width, height);
+        img.build_from_mcus(&self.mcus);
+
+        if self.region.is_some() {
+            img = img.crop(left - x_0, top - y_0, right - left, bottom - top);
         }
+
+        Ok(img)
     }
 
-    fn get_huffman_table_dc(&self, component_id: usize) -> &HuffmanTree {
-        let (huffman_dc, _) = self.component_id_to_huffman_table[&component_id];
+    // Decodes the image one MCU row at a time, handing every band of rows to
+    // `emit` along with the index of its first row. Single scan sequential files
+    // only keep one MCU row in memory, while the coefficients of the other ones
+    // have to be gathered from every scan first.
+    pub fn decode_rows<F>(&mut self, mut emit: F) -> Result<(), DecodeError>
+    where
+        F: FnMut(usize, &Image) -> Result<(), DecodeError>,
+    {
+        self.decode_bands(|top, band, _| emit(top, band))
+    }
 
-        self.huffman_tables
-            .iter()
-            .filter(|table| table.table_type == 0 && table.table_number == huffman_dc)
-            .next()
-            .unwrap()
+    // Same as `decode_rows`, also handing the height of the whole image to `emit`
+    fn decode_bands<F>(&mut self, mut emit: F) -> Result<(), DecodeError>
+    where
+        F: FnMut(usize, &Image, usize) -> Result<(), DecodeError>,
+    {
+        self.streaming = true;
+        let res = self.run(true);
+        self.streaming = false;
+        res?;
+        if self.components.is_empty() {
+            return Err(DecodeError::new(
+                DecodeErrorKind::MissingFrame,
+                self.img_bytes.len(),
+            ));
+        }
+
+        if self.streamed_scan.is_none() {
+            // no scan at all, every coefficient stays zero
+            self.allocate_coefficients(self.img_bytes.len())?;
+        }
+
+        let quantization_tables = self.quantization_tables();
+        let (_, mcu_height) = self.mcu_size();
+        let (_, mcus_y) = self.mcu_grid_size();
+        let width = self.scale.scaled_size(self.img_width);
+        let height = self.scale.scaled_size(self.img_height);
+
+        let mut streamed = self.streamed_scan.take().map(|(offset, length)| {
+            let reader = BitReader::new(&self.img_bytes[offset..offset + length], offset);
+            let (units_x, _, _) = self.scan_units();
+            let coefficients = (0..self.components.len())
+                .map(|comp_id| {
+                    let (blocks_w, _) = self.block_grid_size(comp_id);
+                    vec![[0; 64]; blocks_w * self.components[comp_id].vertical_sampling]
+                })
+                .collect::<Vec<_>>();
+            (
+                reader,
+                ScanState::new(self.components.len()),
+                units_x,
+                coefficients,
+            )
+        });
+
+        for mcu_y in 0..mcus_y {
+            let mcus = match streamed.as_mut() {
+                Some((reader, state, units_x, coefficients)) => {
+                    coefficients
+                        .iter_mut()
+                        .for_each(|blocks| blocks.fill([0; 64]));
+                    let units = mcu_y * *units_x..(mcu_y + 1) * *units_x;
+                    self.decode_units(units, reader, state, coefficients, mcu_y)?;
+                    self.build_mcu_row(
+                        mcu_y,
+                        coefficients,
+                        mcu_y,
+                        &quantization_tables,
+                        self.scale,
+                    )?
+                }
+                None => self.build_mcu_row(
+                    mcu_y,
+                    &self.coefficients,
+                    0,
+                    &quantization_tables,
+                    self.scale,
+                )?,
+            };
+
+            let top = self.scale.scaled_size(mcu_y * mcu_height);
+            let bottom = self
+                .scale
+                .scaled_size(((mcu_y + 1) * mcu_height).min(self.img_height));
+            let mut band = self.new_image(width, bottom - top);
+            band.build_from_mcus(&mcus);
+            emit(top, &band, height)?;
+        }
+
+        Ok(())
     }
 
-    fn get_huffman_table_ac(&self, component_id: usize) -> &HuffmanTree {
-        let (_, huffman_ac) = self.component_id_to_huffman_table[&component_id];
+    // Decodes the image straight into `buffer`, laid out in `pixel_format`
+    // without any padding, one MCU row at a time
+    pub fn decode_into(
+        &mut self,
+        buffer: &mut [u8],
+        pixel_format: PixelFormat,
+    ) -> Result<(), DecodeError> {
+        // the size of the buffer is checked as soon as the frame size is known
+        let previous_format = self.pixel_format.replace(pixel_format);
+        self.output_size = Some(buffer.len());
+        let res = self.decode_bands(|y, band, height| {
+            let band_bytes = band.as_bytes();
+            if pixel_format == PixelFormat::YCbCrPlanar {
+                for plane in 0..3 {
+                    let offset = plane * band.stride() * height + y * band.stride();
+                    let band_plane = band.plane(plane);
+                    buffer[offset..offset + band_plane.len()].copy_from_slice(band_plane);
+                }
+            } else {
+                let offset = y * band.stride();
+                buffer[offset..offset + band_bytes.len()].copy_from_slice(band_bytes);
+            }
+
+            Ok(())
+        });
+        self.pixel_format = previous_format;
+        self.output_size = None;
+
+        res
+    }
 
-        self.huffman_tables
+    // Decodes the quantized coefficients of every component, skipping the IDCT
+    // and the color conversion
+    pub fn decode_coefficients(
+        &mut self,
+        order: CoefficientOrder,
+    ) -> Result<Coefficients, DecodeError> {
+        self.run(true)?;
+        if self.components.is_empty() {
+            return Err(DecodeError::new(
+                DecodeErrorKind::MissingFrame,
+                self.img_bytes.len(),
+            ));
+        }
+        self.allocate_coefficients(self.img_bytes.len())?;
+
+        let quantization_tables = self.quantization_tables();
+        let components = self
+            .components
             .iter()
-            .filter(|table| table.table_type == 1 && table.table_number == huffman_ac)
-            .next()
-            .unwrap()
+            .enumerate()
+            .map(|(comp_id, component)| {
+                let (width_in_blocks, height_in_blocks) = self.block_grid_size(comp_id);
+                ComponentCoefficients {
+                    component: *component,
+                    width_in_blocks,
+                    height_in_blocks,
+                    quantization_table: reorder(&quantization_tables[comp_id], order),
+                    blocks: self.coefficients[comp_id]
+                        .iter()
+                        .map(|zz_block| reorder(zz_block, order))
+                        .collect(),
+                }
+            })
+            .collect();
+
+        Ok(Coefficients {
+            width: self.img_width,
+            height: self.img_height,
+            order,
+            components,
+        })
     }
 
-    fn parse_app0(&mut self, bytes: Vec<u8>) {
-        let mut img_iter = bytes.into_iter();
+    // Parses every marker segment without decoding the image data
+    pub fn inspect(&mut self) -> Result<&[Segment], DecodeError> {
+        self.run(false)?;
 
-        let jfif_string = (0..5)
-            .into_iter()
-            .map(|_| next_byte(&mut img_iter))
-            .collect::<Vec<u8>>();
+        Ok(&self.segments)
+    }
 
-        let jfif_string = match std::str::from_utf8(&jfif_string) {
-            Ok(v) => v,
-            Err(_) => {
-                panic!("[E] - this JPG file may be corrupted, it does not present the 'JFIF\\0' string")
-            }
-        };
+    // Segments found by the last call to `decode` or `inspect`
+    pub fn segments(&self) -> &[Segment] {
+        &self.segments
+    }
 
-        if jfif_string != "JFIF\0" {
-            panic!("[E] - this JPG file may be corrupted, it does not present the 'JFIF\\0' string")
-        }
+    // Frame size and components found by the last call to `decode` or `inspect`
+    pub fn width(&self) -> usize {
+        self.img_width
+    }
 
-        let vers_major = next_byte(&mut img_iter);
-        let vers_minor = next_byte(&mut img_iter);
+    pub fn height(&self) -> usize {
+        self.img_height
+    }
+
+    pub fn components(&self) -> &[Component] {
+        &self.components
+    }
+
+    // Quantization table (in zigzag order) used by every component of the last decoded image
+    pub fn quantization_tables(&self) -> Vec<[u16; 64]> {
+        self.component_quantization
+            .iter()
+            .map(|table| table.unwrap_or([1; 64]))
+            .collect()
+    }
 
-        let density_unit = next_byte(&mut img_iter);
-        let density_horizontal =
-            0x10 * 0x10 * next_byte(&mut img_iter) as u16 + next_byte(&mut img_iter) as u16;
-        let density_vertical =
-            0x10 * 0x10 * next_byte(&mut img_iter) as u16 + next_byte(&mut img_iter) as u16;
-        let thumbnail_horizontal = next_byte(&mut img_iter);
-        let thumbnail_vertical = next_byte(&mut img_iter);
+    // MCUs of the last decoded image (or region), still holding their quantized
+    // coefficients
+    pub fn mcus(&self) -> &[MCU] {
+        &self.mcus
+    }
 
-        if thumbnail_vertical != 0 {
-            // TODO : deal thumbnail if it exists
-            todo!("must deal thumbnail")
+    // Prints a segment-by-segment report of the image to stdout
+    pub fn debug(&mut self) -> Result<(), DecodeError> {
+        let res = self.run(false);
+        for segment in self.segments.iter() {
+            println!("{}", segment);
         }
 
-        println!("\tversion: {}.{}", vers_major, vers_minor);
-        println!(
-            "\tdensity unit: {} (00 for no units, 01 for pixels per inch, 02 for pixels per cm)",
-            density_unit
-        );
-        println!(
-            "\tpixel density: {}x{}",
-            density_horizontal, density_vertical
-        );
-        println!(
-            "\tthumbnail size: {}x{}",
-            thumbnail_horizontal, thumbnail_vertical
-        );
+        res
+    }
+
+    fn run(&mut self, decode_image_data: bool) -> Result<(), DecodeError> {
+        self.huffman_tables = Default::default();
+        self.component_id_to_huffman_table.clear();
+        self.quantization_tables = [None; 4];
+        self.component_quantization.clear();
+        self.components.clear();
+        self.coefficients.clear();
+        self.restart_interval = 0;
+        self.segments.clear();
+        self.mcus.clear();
+        self.streamed_scan = None;
+
+        let img_bytes = std::mem::take(&mut self.img_bytes);
+        let res = self.parse(&img_bytes, decode_image_data);
+        self.img_bytes = img_bytes;
+
+        res
     }
 
-    fn parse_dqt(&mut self, bytes: Vec<u8>) {
-        let mut img_iter = bytes.into_iter();
+    fn parse(&mut self, img_bytes: &[u8], decode_image_data: bool) -> Result<(), DecodeError> {
+        let mut img_iter = ByteReader::new(img_bytes, 0);
 
-        let prec_dest_byte = next_byte(&mut img_iter);
-        let precision = prec_dest_byte >> 4;
-        let table_type = prec_dest_byte << 4 >> 4;
+        if !(img_iter.next_byte()? == JFIF_BYTE_FF && img_iter.next_byte()? == JFIF_SOI) {
+            return Err(DecodeError::new(DecodeErrorKind::NotJpeg, 0));
+        }
+
+        // components whose DC coefficients are fully known, used by `preview`
+        let mut dc_scanned = Vec::new();
+
+        while let Ok(byte) = img_iter.next_byte() {
+            if byte != JFIF_BYTE_FF {
+                continue;
+            }
+            let byte_marker = img_iter.next_byte()?;
+            match byte_marker {
+                JFIF_APP0 => {
+                    let (segment_length, seg) = img_iter.segment()?;
+                    let segment = self.parse_app0(segment_length, seg)?;
+                    self.segments.push(segment);
+                }
+                JFIF_DQT => {
+                    let (segment_length, seg) = img_iter.segment()?;
+                    let segment = self.parse_dqt(segment_length, seg)?;
+                    self.segments.push(segment);
+                }
+                JFIF_DHT => {
+                    let (segment_length, seg) = img_iter.segment()?;
+                    let segment = self.parse_dht(segment_length, seg)?;
+                    self.segments.push(segment);
+                }
+                JFIF_SOF0..=JFIF_SOF2 => {
+                    let (segment_length, seg) = img_iter.segment()?;
+                    let segment = self.parse_sof(byte_marker, segment_length, seg)?;
+                    self.segments.push(segment);
+                }
+                JFIF_SOF3
+                | JFIF_SOF5..=JFIF_SOF7
+                | JFIF_SOF9..=JFIF_SOF11
+                | JFIF_SOF13..=JFIF_SOF15 => {
+                    return Err(img_iter.error(DecodeErrorKind::Unsupported(
+                        "only baseline, extended sequential and progressive frames are supported",
+                    )));
+                }
+                JFIF_SOS => {
+                    let (segment_length, seg) = img_iter.segment()?;
+                    let segment = self.parse_sos(segment_length, seg)?;
+                    self.segments.push(segment);
+
+                    let scan_offset = img_iter.offset();
+                    let image_data = img_iter.entropy_coded_data();
+
+                    if decode_image_data {
+                        // a sequential scan going through every component is the only one
+                        if self.streaming
+                            && !self.progressive
+                            && self.scan.components.len() == self.components.len()
+                        {
+                            self.streamed_scan = Some((scan_offset, image_data.len()));
+                            break;
+                        }
+                        self.allocate_coefficients(scan_offset)?;
+                        self.parse_image_data(image_data, scan_offset)?;
+                    }
 
-        let mut quantization_table = [[0u8; 8]; 8];
-        for i in 0..8 {
-            for j in 0..8 {
-                quantization_table[i][j] = next_byte(&mut img_iter);
+                    if self.dc_only && self.progressive && self.scan.spectral_start == 0 {
+                        dc_scanned.resize(self.components.len(), false);
+                        for comp_id in self.scan.components.iter() {
+                            dc_scanned[*comp_id] = true;
+                        }
+                        if dc_scanned.iter().all(|scanned| *scanned) {
+                            break;
+                        }
+                    }
+                }
+                JFIF_DRI => {
+                    let (segment_length, seg) = img_iter.segment()?;
+                    let segment = self.parse_dri(segment_length, seg)?;
+                    self.segments.push(segment);
+                }
+                JFIF_SOI => {
+                    return Err(DecodeError::new(
+                        DecodeErrorKind::BadMarker(byte_marker),
+                        img_iter.offset() - 2,
+                    ));
+                }
+                JFIF_COM => {
+                    let (length, seg) = img_iter.segment()?;
+                    let text = String::from_utf8_lossy(seg.bytes).into_owned();
+                    self.segments.push(Segment::Comment { length, text });
+                }
+                JFIF_EOI => {
+                    self.segments.push(Segment::EndOfImage);
+                    break;
+                }
+                0x01 | JFIF_RST0..=JFIF_RST7 | JFIF_BYTE_FF => {
+                    // standalone markers and fill bytes carry no segment
+                    self.segments.push(Segment::Unknown {
+                        marker: byte_marker,
+                        length: None,
+                    });
+                }
+                _ => {
+                    let (length, _) = img_iter.segment()?;
+                    self.segments.push(Segment::Unknown {
+                        marker: byte_marker,
+                        length: Some(length),
+                    });
+                }
             }
         }
 
-        if table_type == 0 {
-            self.quantization_table_luma = quantization_table;
-        } else {
-            self.quantization_table_chroma = quantization_table;
+        Ok(())
+    }
+
+    fn get_huffman_table_dc(&self, component_id: usize) -> Option<&HuffmanTree> {
+        let (huffman_dc, _) = self.component_id_to_huffman_table.get(&component_id)?;
+
+        self.huffman_tables[HUFFMAN_DC as usize][*huffman_dc as usize].as_ref()
+    }
+
+    fn get_huffman_table_ac(&self, component_id: usize) -> Option<&HuffmanTree> {
+        let (_, huffman_ac) = self.component_id_to_huffman_table.get(&component_id)?;
+
+        self.huffman_tables[HUFFMAN_AC as usize][*huffman_ac as usize].as_ref()
+    }
+
+    fn missing_huffman_table(&self, class: u8, component_id: usize, offset: usize) -> DecodeError {
+        let id = self
+            .component_id_to_huffman_table
+            .get(&component_id)
+            .map(|(dc, ac)| if class == HUFFMAN_DC { *dc } else { *ac })
+            .unwrap_or(0);
+
+        DecodeError::new(DecodeErrorKind::MissingHuffmanTable { class, id }, offset)
+    }
+
+    fn parse_app0(
+        &mut self,
+        length: u16,
+        mut img_iter: ByteReader,
+    ) -> Result<Segment, DecodeError> {
+        let jfif_string = img_iter.next_n_bytes(5)?;
+
+        if jfif_string != b"JFIF\0" {
+            return Err(DecodeError::new(
+                DecodeErrorKind::MissingJfif,
+                img_iter.offset() - 5,
+            ));
         }
 
-        println!("\tprecision: {} (0 = 8-bit, 1 = 16-bit)", precision);
-        println!("\ttype: {} (0 = luminance, 1 = chrominance)", table_type);
-        println!("\tquantization table:");
-        for i in 0..8 {
-            print!("\t\t");
-            for j in 0..8 {
-                print!("{} ", quantization_table[i][j]);
+        let vers_major = img_iter.next_byte()?;
+        let vers_minor = img_iter.next_byte()?;
+
+        let density_unit = img_iter.next_byte()?;
+        let density_horizontal = img_iter.next_u16()?;
+        let density_vertical = img_iter.next_u16()?;
+        let thumbnail_horizontal = img_iter.next_byte()?;
+        let thumbnail_vertical = img_iter.next_byte()?;
+
+        // the thumbnail (if any) fills the rest of the segment and is skipped
+
+        Ok(Segment::App0 {
+            length,
+            version: (vers_major, vers_minor),
+            density_unit,
+            density: (density_horizontal, density_vertical),
+            thumbnail: (thumbnail_horizontal, thumbnail_vertical),
+        })
+    }
+
+    fn parse_dqt(&mut self, length: u16, mut img_iter: ByteReader) -> Result<Segment, DecodeError> {
+        let mut tables = Vec::new();
+
+        while img_iter.pos < img_iter.bytes.len() {
+            let table_offset = img_iter.offset();
+            let prec_dest_byte = img_iter.next_byte()?;
+            let precision = prec_dest_byte >> 4;
+            let destination = prec_dest_byte & 0x0F;
+
+            if precision > 1 || destination > 3 {
+                return Err(DecodeError::new(
+                    DecodeErrorKind::InvalidSegment(
+                        "quantization table precision or destination out of range",
+                    ),
+                    table_offset,
+                ));
             }
-            println!();
+
+            let mut quantization_table = [0u16; 64];
+            for val in quantization_table.iter_mut() {
+                *val = if precision == 0 {
+                    img_iter.next_byte()? as u16
+                } else {
+                    img_iter.next_u16()?
+                };
+            }
+
+            self.quantization_tables[destination as usize] = Some(quantization_table);
+            tables.push((precision, destination, quantization_table));
         }
+
+        Ok(Segment::Dqt { length, tables })
     }
 
-    fn parse_dht(&mut self, bytes: Vec<u8>) {
-        let mut img_iter = bytes.into_iter();
+    fn parse_dht(&mut self, length: u16, mut img_iter: ByteReader) -> Result<Segment, DecodeError> {
+        let mut tables = Vec::new();
+
+        while img_iter.pos < img_iter.bytes.len() {
+            let table_offset = img_iter.offset();
+            let table_info = img_iter.next_byte()?;
+            let table_type = table_info >> 4;
+            let table_number = table_info & 0x0F;
 
-        let table_info = next_byte(&mut img_iter);
-        let table_type = table_info >> 4;
-        let table_number = table_info << 4 >> 4;
+            if table_type > HUFFMAN_AC || table_number > 3 {
+                return Err(DecodeError::new(
+                    DecodeErrorKind::InvalidSegment("huffman table class or id out of range"),
+                    table_offset,
+                ));
+            }
 
-        let mut characters_len = 0;
-        let mut characters = Vec::new();
-        let mut lengths = Vec::new();
-        let mut zeros_len = 0;
-        for _ in 0..16 {
-            zeros_len += 1;
-            lengths.push(next_byte(&mut img_iter));
-            characters_len += lengths.last().unwrap();
+            let mut lengths = img_iter.next_n_bytes(16)?.to_vec();
+            let characters_len = lengths.iter().map(|l| *l as usize).sum();
 
-            if *lengths.last().unwrap() != 0 {
-                zeros_len = 0;
+            while lengths.last() == Some(&0) {
+                lengths.pop();
             }
+
+            let characters = img_iter.next_n_bytes(characters_len)?;
+
+            let mut huffman_tree = HuffmanTree::new(table_type, table_number);
+            huffman_tree
+                .build(&lengths, characters)
+                .map_err(|kind| DecodeError::new(kind, table_offset))?;
+
+            // a table defined again replaces the previous one for the next scans
+            self.huffman_tables[table_type as usize][table_number as usize] = Some(huffman_tree);
+            tables.push((table_type, table_number, characters_len));
         }
 
-        lengths.truncate(16 - zeros_len);
+        Ok(Segment::Dht { length, tables })
+    }
 
-        for _ in 0..characters_len {
-            characters.push(next_byte(&mut img_iter));
+    fn parse_sof(
+        &mut self,
+        marker: u8,
+        length: u16,
+        mut img_iter: ByteReader,
+    ) -> Result<Segment, DecodeError> {
+        let precision = img_iter.next_byte()?;
+        let img_height = img_iter.next_u16()? as usize;
+        let img_width = img_iter.next_u16()? as usize;
+
+        if precision != 8 {
+            return Err(img_iter.error(DecodeErrorKind::Unsupported(
+                "only 8-bit sample precision is supported",
+            )));
+        }
+        if img_height == 0 || img_width == 0 {
+            return Err(img_iter.error(DecodeErrorKind::InvalidSegment(
+                "image dimensions must not be zero",
+            )));
         }
 
-        let mut huffman_tree = HuffmanTree::new(table_type, table_number);
-        huffman_tree.build(&lengths, &characters);
+        let component_count = img_iter.next_byte()?;
+        if !(component_count == 1 || component_count == 3) {
+            return Err(DecodeError::new(
+                DecodeErrorKind::Unsupported("frames must have 1 or 3 components"),
+                img_iter.offset() - 1,
+            ));
+        }
 
-        self.huffman_tables.push(huffman_tree);
+        let mut components = Vec::new();
+        for _ in 0..component_count {
+            let id = img_iter.next_byte()?;
+            let sampling = img_iter.next_byte()?;
+            let quantization_table = img_iter.next_byte()?;
+            if quantization_table > 3 {
+                return Err(DecodeError::new(
+                    DecodeErrorKind::InvalidSegment("quantization table selector out of range"),
+                    img_iter.offset() - 1,
+                ));
+            }
 
-        println!("\ttable type: {} (0 for DC, 1 for AC)", table_type);
-        println!("\ttable number: {}", table_number);
-        //        println!("\thuffman table lengths:");
-        //        print!("\t\t");
-        //        for i in lengths.iter() {
-        //            print!("{} ", i);
-        //        }
-        //        println!();
-        //        println!("\thuffman table characters:");
-        //        print!("\t\t");
-        //        for i in characters.iter() {
-        //            print!("{:02X} ", i);
-        //        }
-        //        self.huffman_tables.last().unwrap().print();
-        //        println!();
-    }
+            let horizontal_sampling = (sampling >> 4) as usize;
+            let vertical_sampling = (sampling & 0x0F) as usize;
+            if !(1..=4).contains(&horizontal_sampling) || !(1..=4).contains(&vertical_sampling) {
+                return Err(DecodeError::new(
+                    DecodeErrorKind::InvalidSegment("sampling factors must be between 1 and 4"),
+                    img_iter.offset() - 2,
+                ));
+            }
 
-    fn parse_sof(&mut self, bytes: Vec<u8>) {
-        let mut img_iter = bytes.into_iter();
+            components.push(Component {
+                id,
+                horizontal_sampling,
+                vertical_sampling,
+                quantization_table,
+            });
+        }
 
-        let precision = next_byte(&mut img_iter);
-        let img_height =
-            0x10 * 0x10 * next_byte(&mut img_iter) as usize + next_byte(&mut img_iter) as usize;
-        let img_width =
-            0x10 * 0x10 * next_byte(&mut img_iter) as usize + next_byte(&mut img_iter) as usize;
+        let h_max = components
+            .iter()
+            .map(|c| c.horizontal_sampling)
+            .max()
+            .unwrap_or(1);
+        let v_max = components
+            .iter()
+            .map(|c| c.vertical_sampling)
+            .max()
+            .unwrap_or(1);
+        if components.iter().map(|c| c.blocks_per_mcu()).sum::<usize>() > 10 {
+            return Err(img_iter.error(DecodeErrorKind::InvalidSegment(
+                "MCUs can not have more than 10 blocks",
+            )));
+        }
+        if components
+            .iter()
+            .any(|c| h_max % c.horizontal_sampling != 0 || v_max % c.vertical_sampling != 0)
+        {
+            return Err(img_iter.error(DecodeErrorKind::Unsupported(
+                "sampling factors must divide the largest sampling factor",
+            )));
+        }
 
-        self.img_height = img_height as usize;
-        self.img_width = img_width as usize;
+        self.img_height = img_height;
+        self.img_width = img_width;
+        self.progressive = marker == JFIF_SOF2;
+        self.components = components.clone();
+        if let [component] = self.components.as_mut_slice() {
+            // a single component is never interleaved, so every MCU is one block
+            component.horizontal_sampling = 1;
+            component.vertical_sampling = 1;
+        }
+        self.component_quantization = vec![None; self.components.len()];
+        // the blocks are only allocated once a scan has to be decoded
+        self.coefficients.clear();
+
+        if let Some(output_size) = self.output_size {
+            let needed = self.pixel_format().buffer_size(
+                self.scale.scaled_size(img_width),
+                self.scale.scaled_size(img_height),
+            );
+            if output_size < needed {
+                return Err(img_iter.error(DecodeErrorKind::BufferTooSmall { needed }));
+            }
+        }
 
-        println!("\tprecision: {}", precision);
-        println!("\timage size: {}x{}", img_height, img_width);
+        Ok(Segment::Sof {
+            length,
+            marker,
+            precision,
+            height: img_height,
+            width: img_width,
+            components,
+        })
     }
 
-    fn parse_sos0(&mut self, bytes: Vec<u8>) {
-        let mut img_iter = bytes.into_iter();
+    fn parse_dri(&mut self, length: u16, mut img_iter: ByteReader) -> Result<Segment, DecodeError> {
+        let interval = img_iter.next_u16()?;
+        self.restart_interval = interval as usize;
 
-        let component_count = next_byte(&mut img_iter);
-        if component_count != 3 {
-            panic!(
-                "[E] - component number in SOS segment is wrong ({} != 3)",
-                component_count
-            );
+        Ok(Segment::Dri { length, interval })
+    }
+
+    fn parse_sos(&mut self, length: u16, mut img_iter: ByteReader) -> Result<Segment, DecodeError> {
+        if self.components.is_empty() {
+            return Err(img_iter.error(DecodeErrorKind::MissingFrame));
         }
 
-        println!("\thuffman tables for components:");
+        let component_count = img_iter.next_byte()? as usize;
+        if component_count == 0 || component_count > self.components.len() {
+            return Err(DecodeError::new(
+                DecodeErrorKind::InvalidSegment("scan component count out of range"),
+                img_iter.offset() - 1,
+            ));
+        }
+
+        let mut components = Vec::new();
+        let mut scan_components = Vec::new();
         for _ in 0..component_count {
-            let mut component_id = next_byte(&mut img_iter);
-            component_id -= 1;
-            let huffman_table = next_byte(&mut img_iter);
+            let component_id = img_iter.next_byte()?;
+            let component_index = self
+                .components
+                .iter()
+                .position(|c| c.id == component_id)
+                .ok_or_else(|| {
+                    img_iter.error(DecodeErrorKind::InvalidSegment(
+                        "scan refers to a component missing from the frame",
+                    ))
+                })?;
+            if scan_components
+                .last()
+                .is_some_and(|last| *last >= component_index)
+            {
+                return Err(img_iter.error(DecodeErrorKind::InvalidSegment(
+                    "scan components must follow the frame order",
+                )));
+            }
+            let huffman_table = img_iter.next_byte()?;
             let huffman_table_dc = huffman_table >> 4;
-            let huffman_table_ac = huffman_table << 4 >> 4;
+            let huffman_table_ac = huffman_table & 0x0F;
+            if huffman_table_dc > 3 || huffman_table_ac > 3 {
+                return Err(DecodeError::new(
+                    DecodeErrorKind::InvalidSegment("huffman table selector out of range"),
+                    img_iter.offset() - 1,
+                ));
+            }
 
-            println!(
-                "\t\t{} -> {} (dc), {} (ac)",
-                component_id, huffman_table_dc, huffman_table_ac
-            );
+            components.push((component_id, huffman_table_dc, huffman_table_ac));
+            scan_components.push(component_index);
 
             self.component_id_to_huffman_table
-                .insert(component_id as usize, (huffman_table_dc, huffman_table_ac));
+                .insert(component_index, (huffman_table_dc, huffman_table_ac));
+
+            if self.component_quantization[component_index].is_none() {
+                let table_id = self.components[component_index].quantization_table;
+                let table = self.quantization_tables[table_id as usize].ok_or_else(|| {
+                    img_iter.error(DecodeErrorKind::MissingQuantizationTable(table_id))
+                })?;
+                self.component_quantization[component_index] = Some(table);
+            }
         }
+
+        let spectral_start = img_iter.next_byte()?;
+        let spectral_end = img_iter.next_byte()?;
+        let approximation = img_iter.next_byte()?;
+        let approximation_high = approximation >> 4;
+        let approximation_low = approximation & 0x0F;
+
+        let valid = if self.progressive {
+            spectral_start <= spectral_end
+                && spectral_end <= 63
+                && (spectral_start == 0) == (spectral_end == 0)
+                && (spectral_start == 0 || component_count == 1)
+                && approximation_high <= 13
+                && approximation_low <= 13
+        } else {
+            spectral_start == 0 && spectral_end == 63 && approximation == 0
+        };
+        if !valid {
+            return Err(DecodeError::new(
+                DecodeErrorKind::InvalidSegment("invalid spectral selection or approximation"),
+                img_iter.offset() - 3,
+            ));
+        }
+
+        self.scan = Scan {
+            components: scan_components,
+            spectral_start: spectral_start as usize,
+            spectral_end: spectral_end as usize,
+            approximation_high,
+            approximation_low,
+        };
+
+        Ok(Segment::Sos {
+            length,
+            components,
+            spectral_selection: (spectral_start, spectral_end),
+            approximation: (approximation_high, approximation_low),
+        })
     }
 
-    fn parse_image_data(&mut self, img_data: Vec<u8>) {
-        let mcu_count = self.img_width * self.img_height / 64 as usize;
+    fn decode_block(
+        &self,
+        comp_id: usize,
+        reader: &mut BitReader,
+        dc_prediction: &mut i32,
+        zz_block: &mut [i32; 64],
+    ) -> Result<(), DecodeError> {
+        // decode DC
+        let huffman_table = self
+            .get_huffman_table_dc(comp_id)
+            .ok_or_else(|| self.missing_huffman_table(HUFFMAN_DC, comp_id, reader.offset()))?;
+        let category = next_dc_category(reader, huffman_table)?;
+        let dc_coeff = reader.next_bits(category)?;
+        *dc_prediction += bitstring_to_value(dc_coeff, category);
+        zz_block[0] = *dc_prediction;
+
+        // decode AC
+        let huffman_table = self
+            .get_huffman_table_ac(comp_id)
+            .ok_or_else(|| self.missing_huffman_table(HUFFMAN_AC, comp_id, reader.offset()))?;
+        let mut index = 1;
+        while index < 64 {
+            let val = next_symbol(reader, huffman_table)?;
+            let zero_count = (val >> 4) as usize;
+            let category = val & 0x0F;
+
+            if category == 0 {
+                if val != HUFFMAN_ZRL {
+                    // EOB, the remaining coefficients are zero
+                    break;
+                }
+                index += 16;
+                continue;
+            }
 
-        let mut img_bits = Vec::new();
-        for byte in img_data.iter() {
-            let bits = u8_to_bool(*byte);
-            bits.into_iter().for_each(|b| img_bits.push(b));
+            index += zero_count;
+            if index > 63 {
+                return Err(reader.error(DecodeErrorKind::InvalidSegment(
+                    "run length exceeds the 64 coefficients of a block",
+                )));
+            }
+            let ac_coeff = reader.next_bits(category)?;
+            zz_block[index] = bitstring_to_value(ac_coeff, category);
+            index += 1;
         }
 
-        let mut img_bits_iter = img_bits.into_iter();
-        let mut dc_sums = [0; 3];
-        let mut next_dc_sums = [0; 3];
+        Ok(())
+    }
 
-        for mcu_id in 0..mcu_count {
-            let mut run_length_encoding: Vec<Vec<i32>> = vec![Vec::new(), Vec::new(), Vec::new()];
+    fn decode_block_dc_first(
+        &self,
+        comp_id: usize,
+        reader: &mut BitReader,
+        dc_prediction: &mut i32,
+        zz_block: &mut [i32; 64],
+    ) -> Result<(), DecodeError> {
+        let huffman_table = self
+            .get_huffman_table_dc(comp_id)
+            .ok_or_else(|| self.missing_huffman_table(HUFFMAN_DC, comp_id, reader.offset()))?;
+        let category = next_dc_category(reader, huffman_table)?;
+        let dc_coeff = reader.next_bits(category)?;
+        *dc_prediction += bitstring_to_value(dc_coeff, category);
+        zz_block[0] = *dc_prediction << self.scan.approximation_low;
+
+        Ok(())
+    }
 
-            for comp_id in 0..3 {
-                // decode DC
-                let huffman_table = self.get_huffman_table_dc(comp_id);
-                let mut scanned_bits = Vec::new();
-                loop {
-                    scanned_bits.push(next_bit(&mut img_bits_iter));
+    fn decode_block_dc_refine(
+        &self,
+        reader: &mut BitReader,
+        zz_block: &mut [i32; 64],
+    ) -> Result<(), DecodeError> {
+        if reader.next_bit()? {
+            zz_block[0] |= 1 << self.scan.approximation_low;
+        }
 
-                    match huffman_table.try_decode(&scanned_bits) {
-                        HuffmanResult::Some(val) => {
-                            let zero_count = val >> 4;
-                            let category = val << 4 >> 4;
-                            let mut dc_coeff: u16 = 0;
+        Ok(())
+    }
 
-                            for _ in 0..category {
-                                dc_coeff = dc_coeff << 1;
-                                dc_coeff += next_bit(&mut img_bits_iter) as u16;
-                            }
+    fn decode_block_ac_first(
+        &self,
+        comp_id: usize,
+        reader: &mut BitReader,
+        eob_run: &mut u32,
+        zz_block: &mut [i32; 64],
+    ) -> Result<(), DecodeError> {
+        if *eob_run > 0 {
+            *eob_run -= 1;
+            return Ok(());
+        }
 
-                            let dc_coeff = bitstring_to_value(dc_coeff, category);
+        let huffman_table = self
+            .get_huffman_table_ac(comp_id)
+            .ok_or_else(|| self.missing_huffman_table(HUFFMAN_AC, comp_id, reader.offset()))?;
+        let mut index = self.scan.spectral_start;
+        while index <= self.scan.spectral_end {
+            let val = next_symbol(reader, huffman_table)?;
+            let zero_count = val >> 4;
+            let category = val & 0x0F;
+
+            if category == 0 {
+                if val != HUFFMAN_ZRL {
+                    // end of band, this block and the next `eob_run` ones are done
+                    *eob_run = (1 << zero_count) + reader.next_bits(zero_count)? as u32 - 1;
+                    break;
+                }
+                index += 16;
+                continue;
+            }
+
+            index += zero_count as usize;
+            if index > self.scan.spectral_end {
+                return Err(reader.error(DecodeErrorKind::InvalidSegment(
+                    "run length exceeds the spectral selection",
+                )));
+            }
+            let ac_coeff = reader.next_bits(category)?;
+            zz_block[index] = bitstring_to_value(ac_coeff, category) << self.scan.approximation_low;
+            index += 1;
+        }
 
-                            next_dc_sums[comp_id] += dc_coeff;
+        Ok(())
+    }
 
-                            run_length_encoding[comp_id].push(zero_count as i32);
-                            run_length_encoding[comp_id].push(dc_coeff);
-                            break;
+    fn decode_block_ac_refine(
+        &self,
+        comp_id: usize,
+        reader: &mut BitReader,
+        eob_run: &mut u32,
+        zz_block: &mut [i32; 64],
+    ) -> Result<(), DecodeError> {
+        let positive_bit = 1 << self.scan.approximation_low;
+        let negative_bit = -1 << self.scan.approximation_low;
+        let mut index = self.scan.spectral_start;
+
+        if *eob_run == 0 {
+            let huffman_table = self
+                .get_huffman_table_ac(comp_id)
+                .ok_or_else(|| self.missing_huffman_table(HUFFMAN_AC, comp_id, reader.offset()))?;
+
+            while index <= self.scan.spectral_end {
+                let val = next_symbol(reader, huffman_table)?;
+                let mut zero_count = (val >> 4) as i32;
+                let category = val & 0x0F;
+
+                let mut new_coeff = 0;
+                if category != 0 {
+                    if category != 1 {
+                        return Err(reader.error(DecodeErrorKind::InvalidSegment(
+                            "refinement coefficients must have category 1",
+                        )));
+                    }
+                    new_coeff = if reader.next_bit()? {
+                        positive_bit
+                    } else {
+                        negative_bit
+                    };
+                } else if val != HUFFMAN_ZRL {
+                    *eob_run = (1 << zero_count) + reader.next_bits(zero_count as u8)? as u32;
+                    break;
+                }
+
+                // refine the nonzero coefficients up to the `zero_count`-th zero one
+                while index <= self.scan.spectral_end {
+                    let coeff = &mut zz_block[index];
+                    if *coeff != 0 {
+                        if reader.next_bit()? && *coeff & positive_bit == 0 {
+                            *coeff += if *coeff >= 0 {
+                                positive_bit
+                            } else {
+                                negative_bit
+                            };
                         }
-                        HuffmanResult::EOB => {
-                            run_length_encoding[comp_id].push(0);
-                            run_length_encoding[comp_id].push(0);
+                    } else {
+                        zero_count -= 1;
+                        if zero_count < 0 {
                             break;
                         }
-                        HuffmanResult::None => (),
                     }
+                    index += 1;
+                }
+
+                if new_coeff != 0 && index <= self.scan.spectral_end {
+                    zz_block[index] = new_coeff;
                 }
+                index += 1;
+            }
+        }
+
+        if *eob_run > 0 {
+            // only the already nonzero coefficients of the band get a correction bit
+            while index <= self.scan.spectral_end {
+                let coeff = &mut zz_block[index];
+                if *coeff != 0 && reader.next_bit()? && *coeff & positive_bit == 0 {
+                    *coeff += if *coeff >= 0 {
+                        positive_bit
+                    } else {
+                        negative_bit
+                    };
+                }
+                index += 1;
+            }
+            *eob_run -= 1;
+        }
 
-                // decode AC
-                let huffman_table = self.get_huffman_table_ac(comp_id);
-                let mut scanned_bits = Vec::new();
-                let mut ac_count = 0;
-                loop {
-                    if ac_count == 63 {
-                        break;
+        Ok(())
+    }
+
+    fn decode_scan_block(
+        &self,
+        comp_id: usize,
+        reader: &mut BitReader,
+        dc_prediction: &mut i32,
+        eob_run: &mut u32,
+        zz_block: &mut [i32; 64],
+    ) -> Result<(), DecodeError> {
+        let refine = self.scan.approximation_high != 0;
+        match (self.progressive, self.scan.spectral_start == 0, refine) {
+            (false, _, _) => self.decode_block(comp_id, reader, dc_prediction, zz_block),
+            (true, true, false) => {
+                self.decode_block_dc_first(comp_id, reader, dc_prediction, zz_block)
+            }
+            (true, true, true) => self.decode_block_dc_refine(reader, zz_block),
+            (true, false, false) => self.decode_block_ac_first(comp_id, reader, eob_run, zz_block),
+            (true, false, true) => self.decode_block_ac_refine(comp_id, reader, eob_run, zz_block),
+        }
+    }
+
+    fn parse_image_data(&mut self, img_data: &[u8], scan_offset: usize) -> Result<(), DecodeError> {
+        let mut reader = BitReader::new(img_data, scan_offset);
+        let mut coefficients = std::mem::take(&mut self.coefficients);
+        let res = self.decode_scan(&mut reader, &mut coefficients);
+        self.coefficients = coefficients;
+
+        res
+    }
+
+    fn decode_scan(
+        &self,
+        reader: &mut BitReader,
+        coefficients: &mut [Vec<[i32; 64]>],
+    ) -> Result<(), DecodeError> {
+        let (units_x, units_y, _) = self.scan_units();
+
+        // nothing past the last unit of the region needs to be decoded
+        let unit_count = (0..units_x * units_y)
+            .rev()
+            .find(|unit| self.unit_needed(*unit))
+            .map_or(0, |unit| unit + 1);
+        let mut state = ScanState::new(self.components.len());
+        self.decode_units(0..unit_count, reader, &mut state, coefficients, 0)
+    }
+
+    // A non-interleaved scan covers only the blocks inside the image, one at a
+    // time, while an interleaved one goes through whole MCUs. Returns the number
+    // of units in a row and in a column, and the blocks per MCU of a unit.
+    fn scan_units(&self) -> (usize, usize, (usize, usize)) {
+        match self.scan.components[..] {
+            [comp_id] => {
+                let (scan_w, scan_h) = self.component_block_size(comp_id);
+                let component = self.components[comp_id];
+                let sampling = (component.horizontal_sampling, component.vertical_sampling);
+                (scan_w, scan_h, sampling)
+            }
+            _ => {
+                let (mcus_x, mcus_y) = self.mcu_grid_size();
+                (mcus_x, mcus_y, (1, 1))
+            }
+        }
+    }
+
+    // Whether a unit of the current scan belongs to an MCU of the region
+    fn unit_needed(&self, unit: usize) -> bool {
+        if self.region.is_none() {
+            return true;
+        }
+        let (units_x, _, (h, v)) = self.scan_units();
+        let (columns, rows) = self.mcu_window();
+
+        columns.contains(&(unit % units_x / h)) && rows.contains(&(unit / units_x / v))
+    }
+
+    // Decodes `units` of the current scan into `coefficients`, which hold the
+    // blocks of every component from MCU row `first_mcu_row` onwards
+    fn decode_units(
+        &self,
+        units: Range<usize>,
+        reader: &mut BitReader,
+        state: &mut ScanState,
+        coefficients: &mut [Vec<[i32; 64]>],
+        first_mcu_row: usize,
+    ) -> Result<(), DecodeError> {
+        let (units_x, units_y, _) = self.scan_units();
+        for unit in units {
+            self.restart(unit, reader, &mut state.dc_predictions, &mut state.eob_run)?;
+            if self.restart_interval > 0 && unit.is_multiple_of(self.restart_interval) {
+                // the next restart finds its marker without decoding the interval
+                let interval_end = (unit + self.restart_interval).min(units_x * units_y);
+                state.skip_interval = !(unit..interval_end).any(|unit| self.unit_needed(unit));
+            }
+            if state.skip_interval {
+                continue;
+            }
+
+            let (unit_x, unit_y) = (unit % units_x, unit / units_x);
+            if let [comp_id] = self.scan.components[..] {
+                let (blocks_w, _) = self.block_grid_size(comp_id);
+                let first_row = first_mcu_row * self.components[comp_id].vertical_sampling;
+                self.decode_scan_block(
+                    comp_id,
+                    reader,
+                    &mut state.dc_predictions[comp_id],
+                    &mut state.eob_run,
+                    &mut coefficients[comp_id][(unit_y - first_row) * blocks_w + unit_x],
+                )?;
+                continue;
+            }
+
+            for comp_id in self.scan.components.iter().copied() {
+                let component = self.components[comp_id];
+                let (blocks_w, _) = self.block_grid_size(comp_id);
+                for v in 0..component.vertical_sampling {
+                    for h in 0..component.horizontal_sampling {
+                        let x = unit_x * component.horizontal_sampling + h;
+                        let y = (unit_y - first_mcu_row) * component.vertical_sampling + v;
+                        self.decode_scan_block(
+                            comp_id,
+                            reader,
+                            &mut state.dc_predictions[comp_id],
+                            &mut state.eob_run,
+                            &mut coefficients[comp_id][y * blocks_w + x],
+                        )?;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // Every restart interval starts byte aligned, with reset predictions
+    fn restart(
+        &self,
+        unit: usize,
+        reader: &mut BitReader,
+        dc_predictions: &mut [i32],
+        eob_run: &mut u32,
+    ) -> Result<(), DecodeError> {
+        if self.restart_interval == 0 || unit == 0 || !unit.is_multiple_of(self.restart_interval) {
+            return Ok(());
+        }
+
+        dc_predictions.iter_mut().for_each(|pred| *pred = 0);
+        *eob_run = 0;
+        reader.restart(unit / self.restart_interval - 1)
+    }
+
+    // Dequantizes and transforms the coefficients gathered from every scan into MCUs
+    fn build_mcus(&mut self, scale: Scale) -> Result<(), DecodeError> {
+        // components that no scan refers to keep all of their coefficients zero
+        let quantization_tables = self.quantization_tables();
+
+        let (_, rows) = self.mcu_window();
+        let mut mcus = Vec::new();
+        for mcu_y in rows {
+            mcus.extend(self.build_mcu_row(
+                mcu_y,
+                &self.coefficients,
+                0,
+                &quantization_tables,
+                scale,
+            )?);
+        }
+        self.mcus = mcus;
+
+        Ok(())
+    }
+
+    // Builds the MCUs of row `mcu_y` lying in the region, from `coefficients`
+    // holding the blocks of every component from MCU row `first_mcu_row` onwards
+    fn build_mcu_row(
+        &self,
+        mcu_y: usize,
+        coefficients: &[Vec<[i32; 64]>],
+        first_mcu_row: usize,
+        quantization_tables: &[[u16; 64]],
+        scale: Scale,
+    ) -> Result<Vec<MCU>, DecodeError> {
+        let sampling = self
+            .components
+            .iter()
+            .map(|c| (c.horizontal_sampling, c.vertical_sampling))
+            .collect::<Vec<_>>();
+
+        let (mcus_x, _) = self.mcu_grid_size();
+        let (columns, _) = self.mcu_window();
+        let mut mcus = Vec::with_capacity(columns.len());
+        for mcu_x in columns {
+            let mut zz_dct_coeff = Vec::new();
+            for (comp_id, component) in self.components.iter().enumerate() {
+                let (blocks_w, _) = self.block_grid_size(comp_id);
+                let mut blocks = Vec::new();
+                for v in 0..component.vertical_sampling {
+                    for h in 0..component.horizontal_sampling {
+                        let x = mcu_x * component.horizontal_sampling + h;
+                        let y = (mcu_y - first_mcu_row) * component.vertical_sampling + v;
+                        blocks.push(coefficients[comp_id][y * blocks_w + x]);
                     }
-                    scanned_bits.push(next_bit(&mut img_bits_iter));
+                }
+                zz_dct_coeff.push(blocks);
+            }
 
-                    match huffman_table.try_decode(&scanned_bits) {
-                        HuffmanResult::Some(val) => {
-                            let zero_count = val >> 4;
-                            let category = val << 4 >> 4;
-                            let mut ac_coeff: u16 = 0;
+            let mut mcu = MCU::new(mcu_y * mcus_x + mcu_x, zz_dct_coeff, sampling.clone())
+                .map_err(|kind| DecodeError::new(kind, self.img_bytes.len()))?;
+            if self.pixel_format().is_ycbcr() {
+                mcu.build_ycbcr_block(quantization_tables, self.idct_method, scale);
+            } else {
+                mcu.build_rgb_block(quantization_tables, self.idct_method, scale);
+            }
+            mcus.push(mcu);
+        }
 
-                            for _ in 0..category {
-                                ac_coeff = ac_coeff << 1;
-                                ac_coeff += next_bit(&mut img_bits_iter) as u16;
-                            }
+        Ok(mcus)
+    }
 
-                            let ac_coeff = bitstring_to_value(ac_coeff, category);
+    // Zeroed blocks of every component, padded to whole MCUs, unless they are
+    // already allocated or the frame exceeds the pixel limit
+    fn allocate_coefficients(&mut self, offset: usize) -> Result<(), DecodeError> {
+        if !self.coefficients.is_empty() {
+            return Ok(());
+        }
+        let pixels = self.img_width * self.img_height;
+        if pixels > self.max_pixels {
+            return Err(DecodeError::new(
+                DecodeErrorKind::ImageTooLarge { pixels },
+                offset,
+            ));
+        }
 
-                            run_length_encoding[comp_id].push(zero_count as i32);
-                            run_length_encoding[comp_id].push(ac_coeff);
+        self.coefficients = (0..self.components.len())
+            .map(|comp_id| {
+                let (blocks_w, blocks_h) = self.block_grid_size(comp_id);
+                vec![[0; 64]; blocks_w * blocks_h]
+            })
+            .collect();
 
-                            scanned_bits = Vec::new();
-                            ac_count += zero_count + 1;
+        Ok(())
+    }
+
+    fn pixel_format(&self) -> PixelFormat {
+        self.pixel_format.unwrap_or(if self.components.len() == 1 {
+            PixelFormat::Gray8
+        } else {
+            PixelFormat::Rgb8
+        })
+    }
+
+    fn new_image(&self, width: usize, height: usize) -> Image {
+        Image::with_format(width, height, self.pixel_format())
+    }
+
+    fn max_horizontal_sampling(&self) -> usize {
+        self.components
+            .iter()
+            .map(|c| c.horizontal_sampling)
+            .max()
+            .unwrap_or(1)
+    }
+
+    fn max_vertical_sampling(&self) -> usize {
+        self.components
+            .iter()
+            .map(|c| c.vertical_sampling)
+            .max()
+            .unwrap_or(1)
+    }
+
+    // Size of an MCU in pixels
+    fn mcu_size(&self) -> (usize, usize) {
+        (
+            8 * self.max_horizontal_sampling(),
+            8 * self.max_vertical_sampling(),
+        )
+    }
+
+    // Columns and rows of the MCUs overlapping the region being decoded, or of
+    // every MCU when decoding the whole image
+    fn mcu_window(&self) -> (Range<usize>, Range<usize>) {
+        let (mcus_x, mcus_y) = self.mcu_grid_size();
+        match self.region {
+            Some((x, y, width, height)) => {
+                let (mcu_width, mcu_height) = self.mcu_size();
+                let right = x.saturating_add(width).min(self.img_width);
+                let bottom = y.saturating_add(height).min(self.img_height);
+                (
+                    x / mcu_width..right.div_ceil(mcu_width),
+                    y / mcu_height..bottom.div_ceil(mcu_height),
+                )
+            }
+            None => (0..mcus_x, 0..mcus_y),
+        }
+    }
+
+    // Number of MCUs in a row and in a column of the image
+    fn mcu_grid_size(&self) -> (usize, usize) {
+        (
+            self.img_width.div_ceil(8 * self.max_horizontal_sampling()),
+            self.img_height.div_ceil(8 * self.max_vertical_sampling()),
+        )
+    }
+
+    // Number of blocks stored for a component, padded to whole MCUs
+    fn block_grid_size(&self, comp_id: usize) -> (usize, usize) {
+        let (mcus_x, mcus_y) = self.mcu_grid_size();
+        let component = self.components[comp_id];
+
+        (
+            mcus_x * component.horizontal_sampling,
+            mcus_y * component.vertical_sampling,
+        )
+    }
+
+    // Number of blocks needed to cover the (subsampled) component
+    fn component_block_size(&self, comp_id: usize) -> (usize, usize) {
+        let component = self.components[comp_id];
+        let width = (self.img_width * component.horizontal_sampling)
+            .div_ceil(self.max_horizontal_sampling());
+        let height =
+            (self.img_height * component.vertical_sampling).div_ceil(self.max_vertical_sampling());
+
+        (width.div_ceil(8), height.div_ceil(8))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoder::Encoder;
+
+    // A small color gradient, encoded as a baseline file
+    fn gradient_jpeg() -> Vec<u8> {
+        let (width, height) = (24, 16);
+        let data = (0..width * height)
+            .flat_map(|i| [(i % width * 10) as u8, (i / width * 15) as u8, 128])
+            .collect::<Vec<_>>();
+        let img = Image::from_vec(width, height, PixelFormat::Rgb8, data).unwrap();
+
+        Encoder::new(90).encode(&img).unwrap()
+    }
+
+    #[test]
+    fn rejects_huffman_table_selectors_above_3() {
+        let mut bytes = gradient_jpeg();
+        let sos = bytes
+            .windows(2)
+            .position(|marker| marker == [0xFF, JFIF_SOS])
+            .unwrap();
+        // length (2 bytes), component count, then the first component id and
+        // its table selectors
+        bytes[sos + 6] = 0x50;
+
+        let err = Decoder::from_vec(bytes).decode().err().unwrap();
+        assert!(matches!(err.kind(), DecodeErrorKind::InvalidSegment(_)));
+        assert_eq!(err.offset(), sos + 6);
+    }
+
+    #[test]
+    fn inspects_huge_frames_without_allocating_them() {
+        let mut bytes = gradient_jpeg();
+        let sof = bytes
+            .windows(2)
+            .position(|marker| marker == [0xFF, JFIF_SOF0])
+            .unwrap();
+        // height and width follow the length and the precision
+        bytes[sof + 5..sof + 9].copy_from_slice(&[0xFF; 4]);
+
+        let mut decoder = Decoder::from_vec(bytes);
+        decoder.inspect().unwrap();
+        assert_eq!((decoder.width(), decoder.height()), (65535, 65535));
+
+        let err = decoder.decode().err().unwrap();
+        assert!(matches!(
+            err.kind(),
+            DecodeErrorKind::ImageTooLarge {
+                pixels: 4_294_836_225
+            }
+        ));
+
+        let mut decoder = Decoder::from_vec(gradient_jpeg());
+        decoder.set_max_pixels(24 * 16 - 1);
+        assert!(decoder
+            .decode_coefficients(CoefficientOrder::Zigzag)
+            .is_err());
+        decoder.set_max_pixels(24 * 16);
+        assert!(decoder
+            .decode_coefficients(CoefficientOrder::Zigzag)
+            .is_ok());
+    }
+
+    // Encodes grayscale zigzag blocks, one per MCU, as a baseline file and
+    // decodes them back
+    fn round_trip_blocks(blocks: &[[i32; 64]], optimize_huffman: bool) -> Vec<[i32; 64]> {
+        let component = Component {
+            id: 1,
+            horizontal_sampling: 1,
+            vertical_sampling: 1,
+            quantization_table: 0,
+        };
+        let mcus = blocks
+            .iter()
+            .enumerate()
+            .map(|(order, block)| MCU::new(order, vec![vec![*block]], vec![(1, 1)]).unwrap())
+            .collect::<Vec<_>>();
+
+        let mut encoder = Encoder::new(90);
+        encoder.set_optimize_huffman(optimize_huffman);
+        let mut bytes = Vec::new();
+        encoder
+            .encode_mcus(
+                &mut bytes,
+                8 * blocks.len(),
+                8,
+                &[component],
+                &[[1; 64]],
+                &mcus,
+            )
+            .unwrap();
+
+        let coefficients = Decoder::from_vec(bytes)
+            .decode_coefficients(CoefficientOrder::Zigzag)
+            .unwrap();
+        coefficients.components[0].blocks.clone()
+    }
+
+    #[test]
+    fn decodes_zero_dc_differences_and_end_of_blocks() {
+        // equal DCs code a difference of category 0, and every block is a
+        // lone EOB after it
+        let mut flat = [0; 64];
+        flat[0] = -37;
+        let blocks = [[0; 64], [0; 64], flat, flat, flat, [0; 64]];
+
+        for optimize_huffman in [false, true].iter() {
+            assert_eq!(round_trip_blocks(&blocks, *optimize_huffman), blocks);
+        }
+    }
+
+    #[test]
+    fn decodes_runs_of_16_zeros() {
+        let mut blocks = [[0; 64]; 4];
+        // ZRL then a coefficient right after 16 zeros
+        blocks[0][1] = 5;
+        blocks[0][18] = -3;
+        // two ZRLs before the last coefficient, without a trailing EOB
+        blocks[1][0] = 12;
+        blocks[1][31] = 1;
+        blocks[1][63] = -1;
+        // 16 zeros right after the DC
+        blocks[2][0] = 12;
+        blocks[2][17] = 200;
+        // a ZRL-sized run of zeros ending the block is coded as an EOB
+        blocks[3][47] = 2;
+
+        for optimize_huffman in [false, true].iter() {
+            assert_eq!(round_trip_blocks(&blocks, *optimize_huffman), blocks);
+        }
+    }
+
+    #[test]
+    fn decodes_flat_images() {
+        for gray in [0, 128, 255].iter() {
+            let data = vec![*gray; 24 * 16 * 3];
+            let img = Image::from_vec(24, 16, PixelFormat::Rgb8, data).unwrap();
+            let bytes = Encoder::new(75).encode(&img).unwrap();
+
+            // every block but the first one has a DC difference of 0
+            let decoded = Decoder::from_vec(bytes).decode().unwrap();
+            let first = decoded.as_bytes()[0];
+            assert!((first as i32 - *gray as i32).abs() <= 1);
+            assert!(decoded.as_bytes().iter().all(|val| *val == first));
+        }
+    }
+
+    #[test]
+    fn checks_the_output_buffer_at_the_frame() {
+        let bytes = gradient_jpeg();
+        let sof = bytes
+            .windows(2)
+            .position(|marker| marker == [0xFF, JFIF_SOF0])
+            .unwrap();
+        let mut decoder = Decoder::from_vec(bytes);
+        let expected = decoder.decode().unwrap().into_vec();
+        // planar samples follow each other plane after plane
+        let mut planar = vec![0; 24 * 16 * 3];
+        decoder
+            .decode_into(&mut planar, PixelFormat::YCbCrPlanar)
+            .unwrap();
+        decoder.set_pixel_format(PixelFormat::YCbCrPlanar);
+        assert_eq!(planar, decoder.decode().unwrap().into_vec());
+
+        // a too small buffer is rejected right after the SOF segment, before
+        // any scan is parsed
+        let mut buffer = vec![0; 24 * 16 * 3 - 1];
+        let err = decoder
+            .decode_into(&mut buffer, PixelFormat::Rgb8)
+            .err()
+            .unwrap();
+        assert!(matches!(
+            err.kind(),
+            DecodeErrorKind::BufferTooSmall { needed: 1152 }
+        ));
+        assert!(err.offset() > sof && err.offset() < sof + 24);
+        assert!(decoder
+            .segments()
+            .iter()
+            .all(|segment| !matches!(segment, Segment::Sos { .. })));
+
+        buffer.push(0);
+        decoder.decode_into(&mut buffer, PixelFormat::Rgb8).unwrap();
+        assert_eq!(buffer, expected);
+    }
+
+    // A textured color image, so every block holds AC coefficients
+    fn textured_jpeg(
+        width: usize,
+        height: usize,
+        chroma_subsampling: bool,
+        restart_interval: u16,
+    ) -> Vec<u8> {
+        let data = (0..width * height)
+            .flat_map(|i| {
+                let (x, y) = (i % width, i / width);
+                [(x * 5) as u8, (y * 7) as u8, ((x * y) % 256) as u8]
+            })
+            .collect::<Vec<_>>();
+        let img = Image::from_vec(width, height, PixelFormat::Rgb8, data).unwrap();
+
+        let mut encoder = Encoder::new(90);
+        encoder.set_chroma_subsampling(chroma_subsampling);
+        encoder.set_restart_interval(restart_interval);
+        encoder.encode(&img).unwrap()
+    }
+
+    #[test]
+    fn streams_one_band_per_mcu_row() {
+        // 16 pixel high 4:2:0 MCUs, the last row of them holding 8 pixels
+        let mut decoder = Decoder::from_vec(textured_jpeg(40, 40, true, 0));
+        let expected = decoder.decode().unwrap();
+
+        let mut bands = Vec::new();
+        let mut rows = Vec::new();
+        decoder
+            .decode_rows(|top, band| {
+                assert_eq!(band.width(), 40);
+                bands.push((top, band.height()));
+                rows.extend_from_slice(band.as_bytes());
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(bands, [(0, 16), (16, 16), (32, 8)]);
+        assert_eq!(rows, expected.as_bytes());
+    }
+
+    #[test]
+    fn decodes_regions_like_a_crop_of_the_image() {
+        for chroma_subsampling in [false, true].iter() {
+            // restart intervals of 3 MCUs, some of them lying outside the windows
+            let mut decoder = Decoder::from_vec(textured_jpeg(64, 48, *chroma_subsampling, 3));
+            let img = decoder.decode().unwrap();
+
+            let windows = [
+                (10, 13, 30, 20, 30, 20),
+                (50, 40, 100, 100, 14, 8), // clipped at the right and bottom edges
+                (0, 0, 64, 48, 64, 48),
+            ];
+            for (x, y, width, height, clipped_width, clipped_height) in windows.iter() {
+                let region = decoder.decode_region(*x, *y, *width, *height).unwrap();
+                let expected = img.crop(*x, *y, *clipped_width, *clipped_height);
+                assert_eq!(
+                    (region.width(), region.height()),
+                    (*clipped_width, *clipped_height)
+                );
+                assert_eq!(region.as_bytes(), expected.as_bytes());
+            }
+        }
+    }
+
+    // Averages the `factor` x `factor` squares of `img`, clipped to its edges
+    fn box_filter(img: &Image, factor: usize) -> Image {
+        let width = img.width().div_ceil(factor);
+        let height = img.height().div_ceil(factor);
+        let mut data = Vec::with_capacity(width * height * 3);
+        for y in 0..height {
+            for x in 0..width {
+                let mut sums = [0; 3];
+                let mut count = 0;
+                for src_y in y * factor..((y + 1) * factor).min(img.height()) {
+                    for src_x in x * factor..((x + 1) * factor).min(img.width()) {
+                        let rgb = img.get_rgb(src_x, src_y).unwrap();
+                        for (sum, sample) in sums.iter_mut().zip(rgb.iter()) {
+                            *sum += *sample as usize;
                         }
-                        HuffmanResult::EOB => {
-                            run_length_encoding[comp_id].push(0);
-                            run_length_encoding[comp_id].push(0);
-                            break;
+                        count += 1;
+                    }
+                }
+                data.extend(sums.iter().map(|sum| ((sum + count / 2) / count) as u8));
+            }
+        }
+
+        Image::from_vec(width, height, PixelFormat::Rgb8, data).unwrap()
+    }
+
+    #[test]
+    fn scales_sizes_that_are_not_multiples_of_8() {
+        // a smooth gradient, which the box filter and the reduced IDCT agree on
+        let (width, height) = (37, 21);
+        let data = (0..width * height)
+            .flat_map(|i| [(i % width * 6) as u8, (i / width * 10) as u8, 100])
+            .collect::<Vec<_>>();
+        let img = Image::from_vec(width, height, PixelFormat::Rgb8, data).unwrap();
+
+        for chroma_subsampling in [false, true].iter() {
+            let mut encoder = Encoder::new(90);
+            encoder.set_chroma_subsampling(*chroma_subsampling);
+            let bytes = encoder.encode(&img).unwrap();
+            let full = Decoder::from_vec(bytes.clone()).decode().unwrap();
+
+            let scales = [
+                (Scale::Half, 2, (19, 11)),
+                (Scale::Quarter, 4, (10, 6)),
+                (Scale::Eighth, 8, (5, 3)),
+            ];
+            for (scale, factor, size) in scales.iter() {
+                let mut decoder = Decoder::from_vec(bytes.clone());
+                decoder.set_scale(*scale);
+                let scaled = decoder.decode().unwrap();
+                assert_eq!((scaled.width(), scaled.height()), *size);
+
+                // subsampled chroma is not upsampled within the scaled pixels, and
+                // the edge blocks also average the padding
+                if *chroma_subsampling {
+                    continue;
+                }
+                let expected = box_filter(&full, *factor);
+                for y in 0..height / factor {
+                    for x in 0..width / factor {
+                        let rgb = scaled.get_rgb(x, y).unwrap();
+                        let expected_rgb = expected.get_rgb(x, y).unwrap();
+                        for (a, b) in rgb.iter().zip(expected_rgb.iter()) {
+                            assert!(a.abs_diff(*b) <= 4, "{:?} at ({}, {})", scale, x, y);
                         }
-                        HuffmanResult::None => (),
                     }
                 }
             }
-            let mut mcu = MCU::new(mcu_id, run_length_encoding, dc_sums);
-            mcu.build_rgb_block(
-                &self.quantization_table_luma,
-                &self.quantization_table_chroma,
-            );
-            self.mcus.push(mcu);
+        }
+    }
+
+    #[test]
+    fn previews_like_an_eighth_scale_decode() {
+        for chroma_subsampling in [false, true].iter() {
+            let bytes = textured_jpeg(37, 21, *chroma_subsampling, 2);
+            let mut decoder = Decoder::from_vec(bytes);
+            let preview = decoder.preview().unwrap();
+            assert_eq!((preview.width(), preview.height()), (5, 3));
+
+            decoder.set_scale(Scale::Eighth);
+            assert_eq!(preview.as_bytes(), decoder.decode().unwrap().as_bytes());
+        }
+    }
 
-            dc_sums = next_dc_sums;
+    // Checks that `values` holds the zigzag ordered `zz_values` row by row
+    fn assert_natural_order<T: Copy + PartialEq + std::fmt::Debug>(
+        zz_values: &[T; 64],
+        values: &[T; 64],
+    ) {
+        // zigzag positions 1 to 5 go to (0, 1), (1, 0), (2, 0), (1, 1) and (0, 2)
+        for (zz_index, index) in [0, 1, 8, 16, 9, 2].iter().enumerate() {
+            assert_eq!(values[*index], zz_values[zz_index]);
+        }
+        assert_eq!(values[63], zz_values[63]);
+        for (v, row) in crate::mcu::ZIGZAG.iter().enumerate() {
+            for (u, zz_index) in row.iter().enumerate() {
+                assert_eq!(values[v * 8 + u], zz_values[*zz_index]);
+            }
         }
+    }
 
-        let mut img = Image::new(self.img_width, self.img_height);
-        img.build_from_mcus(&self.mcus);
-        img.dump_to_ppm(&format!("{}.ppm", self.img_path)).unwrap();
+    #[test]
+    fn reorders_coefficients_of_subsampled_components() {
+        // 4:2:0 MCUs of 16x16 pixels, 3 across and 2 down the 40x24 frame
+        let mut decoder = Decoder::from_vec(textured_jpeg(40, 24, true, 0));
+        let zigzag = decoder
+            .decode_coefficients(CoefficientOrder::Zigzag)
+            .unwrap();
+        let natural = decoder
+            .decode_coefficients(CoefficientOrder::Natural)
+            .unwrap();
+        assert_eq!((natural.width, natural.height), (40, 24));
+
+        let grid_sizes = natural
+            .components
+            .iter()
+            .map(|c| (c.width_in_blocks, c.height_in_blocks, c.blocks.len()))
+            .collect::<Vec<_>>();
+        assert_eq!(grid_sizes, [(6, 4, 24), (3, 2, 6), (3, 2, 6)]);
+        assert!(natural.components[0].block(6, 0).is_none());
+        assert_ne!(
+            natural.components[0].quantization_table,
+            natural.components[1].quantization_table
+        );
+
+        for (zz, nat) in zigzag.components.iter().zip(natural.components.iter()) {
+            assert_natural_order(&zz.quantization_table, &nat.quantization_table);
+            for (zz_block, block) in zz.blocks.iter().zip(nat.blocks.iter()) {
+                assert_natural_order(zz_block, block);
+            }
+        }
     }
 }
@@ -0,0 +1,1026 @@
+use crate::bit_writer::BitWriter;
+use crate::codes_markers::*;
+use crate::component::Component;
+use crate::idct::cosine_table;
+use crate::image::{Image, PixelFormat};
+use crate::mcu::{MCU, ZIGZAG};
+use std::fs;
+use std::io;
+use std::io::Write;
+
+// Quantization tables from Annex K.1 of the JPEG standard, in natural order
+const LUMINANCE_QUANTIZATION: [u16; 64] = [
+    16, 11, 10, 16, 24, 40, 51, 61, //
+    12, 12, 14, 19, 26, 58, 60, 55, //
+    14, 13, 16, 24, 40, 57, 69, 56, //
+    14, 17, 22, 29, 51, 87, 80, 62, //
+    18, 22, 37, 56, 68, 109, 103, 77, //
+    24, 35, 55, 64, 81, 104, 113, 92, //
+    49, 64, 78, 87, 103, 121, 120, 101, //
+    72, 92, 95, 98, 112, 100, 103, 99, //
+];
+const CHROMINANCE_QUANTIZATION: [u16; 64] = [
+    17, 18, 24, 47, 99, 99, 99, 99, //
+    18, 21, 26, 66, 99, 99, 99, 99, //
+    24, 26, 56, 99, 99, 99, 99, 99, //
+    47, 66, 99, 99, 99, 99, 99, 99, //
+    99, 99, 99, 99, 99, 99, 99, 99, //
+    99, 99, 99, 99, 99, 99, 99, 99, //
+    99, 99, 99, 99, 99, 99, 99, 99, //
+    99, 99, 99, 99, 99, 99, 99, 99, //
+];
+
+// Huffman tables from Annex K.3: number of codes of every length, then the symbols
+pub const DC_LUMINANCE_LENGTHS: [u8; 16] = [0, 1, 5, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0];
+pub const DC_LUMINANCE_VALUES: [u8; 12] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11];
+pub const DC_CHROMINANCE_LENGTHS: [u8; 16] = [0, 3, 1, 1, 1, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0];
+pub const DC_CHROMINANCE_VALUES: [u8; 12] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11];
+pub const AC_LUMINANCE_LENGTHS: [u8; 16] = [0, 2, 1, 3, 3, 2, 4, 3, 5, 5, 4, 4, 0, 0, 1, 0x7D];
+pub const AC_LUMINANCE_VALUES: [u8; 162] = [
+    0x01, 0x02, 0x03, 0x00, 0x04, 0x11, 0x05, 0x12, 0x21, 0x31, 0x41, 0x06, 0x13, 0x51, 0x61, 0x07,
+    0x22, 0x71, 0x14, 0x32, 0x81, 0x91, 0xA1, 0x08, 0x23, 0x42, 0xB1, 0xC1, 0x15, 0x52, 0xD1, 0xF0,
+    0x24, 0x33, 0x62, 0x72, 0x82, 0x09, 0x0A, 0x16, 0x17, 0x18, 0x19, 0x1A, 0x25, 0x26, 0x27, 0x28,
+    0x29, 0x2A, 0x34, 0x35, 0x36, 0x37, 0x38, 0x39, 0x3A, 0x43, 0x44, 0x45, 0x46, 0x47, 0x48, 0x49,
+    0x4A, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58, 0x59, 0x5A, 0x63, 0x64, 0x65, 0x66, 0x67, 0x68, 0x69,
+    0x6A, 0x73, 0x74, 0x75, 0x76, 0x77, 0x78, 0x79, 0x7A, 0x83, 0x84, 0x85, 0x86, 0x87, 0x88, 0x89,
+    0x8A, 0x92, 0x93, 0x94, 0x95, 0x96, 0x97, 0x98, 0x99, 0x9A, 0xA2, 0xA3, 0xA4, 0xA5, 0xA6, 0xA7,
+    0xA8, 0xA9, 0xAA, 0xB2, 0xB3, 0xB4, 0xB5, 0xB6, 0xB7, 0xB8, 0xB9, 0xBA, 0xC2, 0xC3, 0xC4, 0xC5,
+    0xC6, 0xC7, 0xC8, 0xC9, 0xCA, 0xD2, 0xD3, 0xD4, 0xD5, 0xD6, 0xD7, 0xD8, 0xD9, 0xDA, 0xE1, 0xE2,
+    0xE3, 0xE4, 0xE5, 0xE6, 0xE7, 0xE8, 0xE9, 0xEA, 0xF1, 0xF2, 0xF3, 0xF4, 0xF5, 0xF6, 0xF7, 0xF8,
+    0xF9, 0xFA,
+];
+pub const AC_CHROMINANCE_LENGTHS: [u8; 16] = [0, 2, 1, 2, 4, 4, 3, 4, 7, 5, 4, 4, 0, 1, 2, 0x77];
+pub const AC_CHROMINANCE_VALUES: [u8; 162] = [
+    0x00, 0x01, 0x02, 0x03, 0x11, 0x04, 0x05, 0x21, 0x31, 0x06, 0x12, 0x41, 0x51, 0x07, 0x61, 0x71,
+    0x13, 0x22, 0x32, 0x81, 0x08, 0x14, 0x42, 0x91, 0xA1, 0xB1, 0xC1, 0x09, 0x23, 0x33, 0x52, 0xF0,
+    0x15, 0x62, 0x72, 0xD1, 0x0A, 0x16, 0x24, 0x34, 0xE1, 0x25, 0xF1, 0x17, 0x18, 0x19, 0x1A, 0x26,
+    0x27, 0x28, 0x29, 0x2A, 0x35, 0x36, 0x37, 0x38, 0x39, 0x3A, 0x43, 0x44, 0x45, 0x46, 0x47, 0x48,
+    0x49, 0x4A, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58, 0x59, 0x5A, 0x63, 0x64, 0x65, 0x66, 0x67, 0x68,
+    0x69, 0x6A, 0x73, 0x74, 0x75, 0x76, 0x77, 0x78, 0x79, 0x7A, 0x82, 0x83, 0x84, 0x85, 0x86, 0x87,
+    0x88, 0x89, 0x8A, 0x92, 0x93, 0x94, 0x95, 0x96, 0x97, 0x98, 0x99, 0x9A, 0xA2, 0xA3, 0xA4, 0xA5,
+    0xA6, 0xA7, 0xA8, 0xA9, 0xAA, 0xB2, 0xB3, 0xB4, 0xB5, 0xB6, 0xB7, 0xB8, 0xB9, 0xBA, 0xC2, 0xC3,
+    0xC4, 0xC5, 0xC6, 0xC7, 0xC8, 0xC9, 0xCA, 0xD2, 0xD3, 0xD4, 0xD5, 0xD6, 0xD7, 0xD8, 0xD9, 0xDA,
+    0xE2, 0xE3, 0xE4, 0xE5, 0xE6, 0xE7, 0xE8, 0xE9, 0xEA, 0xF2, 0xF3, 0xF4, 0xF5, 0xF6, 0xF7, 0xF8,
+    0xF9, 0xFA,
+];
+
+// Writes images as baseline JFIF files
+pub struct Encoder {
+    quality: u8,              // 1 (smallest file) to 100 (best quality)
+    chroma_subsampling: bool, // 4:2:0 instead of 4:4:4 for color images
+    optimize_huffman: bool,
+    restart_interval: u16,        // MCUs between RSTn markers, 0 when disabled
+    segments: Vec<(u8, Vec<u8>)>, // APPn and COM segments written after the APP0 one
+}
+
+// Canonical codes of a Huffman table, as written in a DHT segment
+struct HuffmanTable {
+    class: u8,
+    id: u8,
+    lengths: [u8; 16],
+    vals: Vec<u8>,
+    codes: [(u16, u8); 256], // (code, length) of every symbol, length 0 when missing
+}
+
+impl HuffmanTable {
+    fn new(class: u8, id: u8, lengths: &[u8; 16], vals: &[u8]) -> Self {
+        let mut codes = [(0, 0); 256];
+        let mut code: u16 = 0;
+        let mut val_index = 0;
+        for (i, l) in lengths.iter().enumerate() {
+            for _ in 0..*l {
+                codes[vals[val_index] as usize] = (code, i as u8 + 1);
+                code += 1;
+                val_index += 1;
+            }
+            code <<= 1;
+        }
+
+        Self {
+            class,
+            id,
+            lengths: *lengths,
+            vals: vals.to_vec(),
+            codes,
+        }
+    }
+
+    fn write_symbol(&self, writer: &mut BitWriter, symbol: u8) -> io::Result<()> {
+        let (code, length) = self.codes[symbol as usize];
+        if length == 0 {
+            return Err(invalid_input("symbol missing from the huffman table"));
+        }
+        writer.write_bits(code, length);
+
+        Ok(())
+    }
+}
+
+// Number of bits of the magnitude of `value`, and those bits as written after
+// its symbol: negative values are stored as their one's complement
+fn category_and_bits(value: i32) -> (u8, u16) {
+    let category = (32 - value.unsigned_abs().leading_zeros()) as u8;
+    let bits = if value < 0 { value - 1 } else { value };
+
+    (category, bits as u16)
+}
+
+// Scales an Annex K table the way libjpeg does and returns it in zigzag order
+fn scaled_quantization_table(table: &[u16; 64], quality: u8) -> [u16; 64] {
+    let quality = quality.clamp(1, 100) as u32;
+    let scale = if quality < 50 {
+        5000 / quality
+    } else {
+        200 - 2 * quality
+    };
+
+    let mut zz_table = [0; 64];
+    for (i, zz_index) in ZIGZAG.iter().flatten().enumerate() {
+        let val = (table[i] as u32 * scale + 50) / 100;
+        zz_table[*zz_index] = val.clamp(1, 255) as u16;
+    }
+
+    zz_table
+}
+
+// Forward DCT of a level shifted block, returned in natural order
+fn fdct(block: &[[f64; 8]; 8]) -> [f64; 64] {
+    let cosines = cosine_table();
+
+    // 1-D transform of every row of samples
+    let mut temp = [[0.0; 8]; 8];
+    for (y, row) in temp.iter_mut().enumerate() {
+        for (u, val) in row.iter_mut().enumerate() {
+            *val = block[y]
+                .iter()
+                .enumerate()
+                .map(|(x, sample)| sample * cosines[x][u])
+                .sum();
+        }
+    }
+
+    // 1-D transform of every column of the result
+    let mut coefficients = [0.0; 64];
+    for (i, val) in coefficients.iter_mut().enumerate() {
+        let (v, u) = (i / 8, i % 8);
+        *val = temp
+            .iter()
+            .enumerate()
+            .map(|(y, temp_row)| temp_row[u] * cosines[y][v])
+            .sum();
+    }
+
+    coefficients
+}
+
+fn write_marker<W: Write>(writer: &mut W, marker: u8) -> io::Result<()> {
+    writer.write_all(&[JFIF_BYTE_FF, marker])
+}
+
+// Writes a marker followed by the length of its segment and its contents
+fn write_segment<W: Write>(writer: &mut W, marker: u8, contents: &[u8]) -> io::Result<()> {
+    write_marker(writer, marker)?;
+    writer.write_all(&(contents.len() as u16 + 2).to_be_bytes())?;
+    writer.write_all(contents)
+}
+
+impl Encoder {
+    pub fn new(quality: u8) -> Self {
+        Self {
+            quality: quality.clamp(1, 100),
+            chroma_subsampling: false,
+            optimize_huffman: false,
+            restart_interval: 0,
+            segments: Vec::new(),
+        }
+    }
+
+    pub fn set_chroma_subsampling(&mut self, chroma_subsampling: bool) {
+        self.chroma_subsampling = chroma_subsampling;
+    }
+
+    // Builds Huffman tables fitted to the image instead of using the Annex K ones,
+    // at the cost of a second pass over the coefficients
+    pub fn set_optimize_huffman(&mut self, optimize_huffman: bool) {
+        self.optimize_huffman = optimize_huffman;
+    }
+
+    // Resets the DC predictions every `restart_interval` MCUs, behind RSTn markers
+    // that let decoders resynchronize after corrupt data
+    pub fn set_restart_interval(&mut self, restart_interval: u16) {
+        self.restart_interval = restart_interval;
+    }
+
+    // Adds an APPn (such as EXIF or ICC data) or COM segment to the files written
+    pub fn add_segment(&mut self, marker: u8, contents: &[u8]) -> io::Result<()> {
+        if !matches!(marker, 0xE1..=0xEF | JFIF_COM) {
+            return Err(invalid_input(
+                "only APP1 to APP15 and COM segments can be added",
+            ));
+        }
+        if contents.len() > u16::MAX as usize - 2 {
+            return Err(invalid_input("segment too long"));
+        }
+        self.segments.push((marker, contents.to_vec()));
+
+        Ok(())
+    }
+
+    pub fn encode(&self, img: &Image) -> io::Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        self.encode_to(img, &mut bytes)?;
+
+        Ok(bytes)
+    }
+
+    pub fn write_to_file(&self, img: &Image, path: &str) -> io::Result<()> {
+        fs::write(path, self.encode(img)?)
+    }
+
+    pub fn encode_to<W: Write>(&self, img: &Image, writer: &mut W) -> io::Result<()> {
+        check_size(img.width(), img.height())?;
+
+        let components = self.components(img);
+        let luma_table = scaled_quantization_table(&LUMINANCE_QUANTIZATION, self.quality);
+        let chroma_table = scaled_quantization_table(&CHROMINANCE_QUANTIZATION, self.quality);
+        let quantization_tables = components
+            .iter()
+            .map(|c| {
+                if c.quantization_table == 0 {
+                    luma_table
+                } else {
+                    chroma_table
+                }
+            })
+            .collect::<Vec<_>>();
+
+        let mcus = self.quantized_mcus(img, &components, &quantization_tables)?;
+
+        self.encode_mcus(
+            writer,
+            img.width(),
+            img.height(),
+            &components,
+            &quantization_tables,
+            &mcus,
+        )
+    }
+
+    // Writes already quantized blocks, such as the ones of a decoded image, as a
+    // baseline file. `quantization_tables` holds the table (in zigzag order) of
+    // every component, and the MCUs must cover the frame row by row.
+    pub fn encode_mcus<W: Write>(
+        &self,
+        writer: &mut W,
+        width: usize,
+        height: usize,
+        components: &[Component],
+        quantization_tables: &[[u16; 64]],
+        mcus: &[MCU],
+    ) -> io::Result<()> {
+        check_size(width, height)?;
+        if !(components.len() == 1 || components.len() == 3)
+            || quantization_tables.len() != components.len()
+        {
+            return Err(invalid_input("frames need 1 or 3 components"));
+        }
+        let h_max = components.iter().map(|c| c.horizontal_sampling).max();
+        let v_max = components.iter().map(|c| c.vertical_sampling).max();
+        let mcu_count =
+            width.div_ceil(8 * h_max.unwrap_or(1)) * height.div_ceil(8 * v_max.unwrap_or(1));
+        let blocks_match = mcus.iter().all(|mcu| {
+            mcu.zz_dct_coeff.len() == components.len()
+                && mcu
+                    .zz_dct_coeff
+                    .iter()
+                    .zip(components.iter())
+                    .all(|(blocks, c)| blocks.len() == c.blocks_per_mcu())
+        });
+        if mcus.len() != mcu_count || !blocks_match {
+            return Err(invalid_input("MCUs do not match the frame"));
+        }
+        check_categories(mcus, self.restart_interval)?;
+
+        // components with equal tables share a DQT slot
+        let mut slots: Vec<[u16; 64]> = Vec::new();
+        let mut components = components.to_vec();
+        for (component, table) in components.iter_mut().zip(quantization_tables.iter()) {
+            let slot = match slots.iter().position(|t| t == table) {
+                Some(slot) => slot,
+                None => {
+                    slots.push(*table);
+                    slots.len() - 1
+                }
+            };
+            if slot > 3 {
+                return Err(invalid_input("more than 4 quantization tables"));
+            }
+            component.quantization_table = slot as u8;
+        }
+
+        let huffman_tables = if self.optimize_huffman {
+            optimized_huffman_tables(mcus, components.len(), self.restart_interval)
+        } else {
+            standard_huffman_tables(components.len())
+        };
+
+        write_marker(writer, JFIF_SOI)?;
+        write_app0(writer)?;
+        for (marker, contents) in self.segments.iter() {
+            write_segment(writer, *marker, contents)?;
+        }
+        write_dqt(writer, &slots)?;
+        write_sof(writer, width, height, &components, &slots)?;
+        write_dht(writer, &huffman_tables)?;
+        if self.restart_interval > 0 {
+            write_segment(writer, JFIF_DRI, &self.restart_interval.to_be_bytes())?;
+        }
+        write_sos(writer, &components)?;
+        writer.write_all(&encode_scan(mcus, &huffman_tables, self.restart_interval)?)?;
+        write_marker(writer, JFIF_EOI)
+    }
+
+    fn components(&self, img: &Image) -> Vec<Component> {
+        if img.is_grayscale() {
+            return vec![Component {
+                id: 1,
+                horizontal_sampling: 1,
+                vertical_sampling: 1,
+                quantization_table: 0,
+            }];
+        }
+
+        let luma_sampling = if self.chroma_subsampling { 2 } else { 1 };
+        (1..=3)
+            .map(|id| Component {
+                id,
+                horizontal_sampling: if id == 1 { luma_sampling } else { 1 },
+                vertical_sampling: if id == 1 { luma_sampling } else { 1 },
+                quantization_table: if id == 1 { 0 } else { 1 },
+            })
+            .collect()
+    }
+
+    // Converts the image to Y, Cb and Cr planes padded to whole MCUs and splits
+    // them into quantized blocks
+    fn quantized_mcus(
+        &self,
+        img: &Image,
+        components: &[Component],
+        quantization_tables: &[[u16; 64]],
+    ) -> io::Result<Vec<MCU>> {
+        let h_max = components[0].horizontal_sampling;
+        let v_max = components[0].vertical_sampling;
+        let mcus_x = img.width().div_ceil(8 * h_max);
+        let mcus_y = img.height().div_ceil(8 * v_max);
+
+        let planes = ycbcr_planes(img, mcus_x * 8 * h_max, mcus_y * 8 * v_max);
+        let planes = planes
+            .into_iter()
+            .zip(components.iter())
+            .map(|(plane, c)| downsample(plane, h_max / c.horizontal_sampling))
+            .collect::<Vec<_>>();
+        let sampling = components
+            .iter()
+            .map(|c| (c.horizontal_sampling, c.vertical_sampling))
+            .collect::<Vec<_>>();
+
+        let mut mcus = Vec::with_capacity(mcus_x * mcus_y);
+        for mcu_id in 0..mcus_x * mcus_y {
+            let (mcu_x, mcu_y) = (mcu_id % mcus_x, mcu_id / mcus_x);
+
+            let mut zz_dct_coeff = Vec::new();
+            for (comp_id, component) in components.iter().enumerate() {
+                let plane = &planes[comp_id];
+                let mut blocks = Vec::new();
+                for v in 0..component.vertical_sampling {
+                    for h in 0..component.horizontal_sampling {
+                        let x_0 = (mcu_x * component.horizontal_sampling + h) * 8;
+                        let y_0 = (mcu_y * component.vertical_sampling + v) * 8;
+
+                        let mut block = [[0.0; 8]; 8];
+                        for (y, row) in block.iter_mut().enumerate() {
+                            for (x, val) in row.iter_mut().enumerate() {
+                                *val = plane[y_0 + y][x_0 + x] - 128.0;
+                            }
+                        }
+                        blocks.push(quantize(&fdct(&block), &quantization_tables[comp_id]));
+                    }
+                }
+                zz_dct_coeff.push(blocks);
+            }
+
+            let mcu = MCU::new(mcu_id, zz_dct_coeff, sampling.clone())
+                .map_err(|err| invalid_input(&err.to_string()))?;
+            mcus.push(mcu);
+        }
+
+        Ok(mcus)
+    }
+}
+
+fn invalid_input(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, reason.to_string())
+}
+
+fn check_size(width: usize, height: usize) -> io::Result<()> {
+    if width == 0 || height == 0 || width > 0xFFFF || height > 0xFFFF {
+        return Err(invalid_input(
+            "image size must be between 1 and 65535 pixels",
+        ));
+    }
+
+    Ok(())
+}
+
+// Divides the coefficients by the quantization table, reordering them in zigzag order
+fn quantize(coefficients: &[f64; 64], quantization: &[u16; 64]) -> [i32; 64] {
+    let mut zz_block = [0; 64];
+    for (i, zz_index) in ZIGZAG.iter().flatten().enumerate() {
+        zz_block[*zz_index] = (coefficients[i] / quantization[*zz_index] as f64).round() as i32;
+    }
+
+    zz_block
+}
+
+// Luma plane of grayscale images, or the JFIF Y, Cb and Cr planes of color ones,
+// padded to `width` x `height` by repeating the last column and row
+fn ycbcr_planes(img: &Image, width: usize, height: usize) -> Vec<Vec<Vec<f64>>> {
+    // samples past the edges repeat the last row and column
+    let edge = |x: usize, y: usize| (x.min(img.width() - 1), y.min(img.height() - 1));
+
+    if img.is_grayscale() {
+        let luma = (0..height)
+            .map(|y| {
+                (0..width)
+                    .map(|x| {
+                        let (x, y) = edge(x, y);
+                        img.get_luma(x, y).unwrap_or_default() as f64
+                    })
+                    .collect()
+            })
+            .collect();
+        return vec![luma];
+    }
+
+    let mut ycbcr = vec![vec![vec![0.0; width]; height]; 3];
+    for y in 0..height {
+        for x in 0..width {
+            let (x_in, y_in) = edge(x, y);
+            let pixel = match img.format() {
+                PixelFormat::YCbCrPlanar => {
+                    let [y, cb, cr, _] = img.get_pixel(x_in, y_in).unwrap_or_default();
+                    [y, cb, cr].map(|val| val as f64)
+                }
+                _ => {
+                    let [r, g, b] = img
+                        .get_rgb(x_in, y_in)
+                        .unwrap_or_default()
+                        .map(|val| val as f64);
+                    [
+                        0.299 * r + 0.587 * g + 0.114 * b,
+                        -0.168736 * r - 0.331264 * g + 0.5 * b + 128.0,
+                        0.5 * r - 0.418688 * g - 0.081312 * b + 128.0,
+                    ]
+                }
+            };
+            for (plane, val) in ycbcr.iter_mut().zip(pixel.iter()) {
+                plane[y][x] = *val;
+            }
+        }
+    }
+
+    ycbcr
+}
+
+// Averages every `factor` x `factor` square of samples
+fn downsample(plane: Vec<Vec<f64>>, factor: usize) -> Vec<Vec<f64>> {
+    if factor == 1 {
+        return plane;
+    }
+
+    let width = plane[0].len() / factor;
+    let height = plane.len() / factor;
+    let area = (factor * factor) as f64;
+    (0..height)
+        .map(|y| {
+            (0..width)
+                .map(|x| {
+                    let rows = plane[y * factor..(y + 1) * factor].iter();
+                    rows.map(|row| row[x * factor..(x + 1) * factor].iter().sum::<f64>())
+                        .sum::<f64>()
+                        / area
+                })
+                .collect()
+        })
+        .collect()
+}
+
+// Id of the DC and AC tables of a component: the luma tables for the first
+// component, the chroma ones for the others
+fn huffman_table_id(comp_id: usize) -> u8 {
+    if comp_id == 0 {
+        0
+    } else {
+        1
+    }
+}
+
+fn standard_huffman_tables(component_count: usize) -> Vec<HuffmanTable> {
+    let mut tables = vec![
+        HuffmanTable::new(HUFFMAN_DC, 0, &DC_LUMINANCE_LENGTHS, &DC_LUMINANCE_VALUES),
+        HuffmanTable::new(HUFFMAN_AC, 0, &AC_LUMINANCE_LENGTHS, &AC_LUMINANCE_VALUES),
+    ];
+    if component_count > 1 {
+        tables.push(HuffmanTable::new(
+            HUFFMAN_DC,
+            1,
+            &DC_CHROMINANCE_LENGTHS,
+            &DC_CHROMINANCE_VALUES,
+        ));
+        tables.push(HuffmanTable::new(
+            HUFFMAN_AC,
+            1,
+            &AC_CHROMINANCE_LENGTHS,
+            &AC_CHROMINANCE_VALUES,
+        ));
+    }
+
+    tables
+}
+
+// Counts the symbols every table would encode and builds the tables from them
+fn optimized_huffman_tables(
+    mcus: &[MCU],
+    component_count: usize,
+    restart_interval: u16,
+) -> Vec<HuffmanTable> {
+    let table_count = if component_count > 1 { 2 } else { 1 };
+    let mut frequencies = vec![[[0u32; 256]; 2]; table_count]; // by id, then class
+
+    let mut dc_predictions = vec![0; component_count];
+    for (mcu_index, mcu) in mcus.iter().enumerate() {
+        if starts_interval(mcu_index, restart_interval) {
+            dc_predictions.fill(0);
+        }
+        for (comp_id, blocks) in mcu.zz_dct_coeff.iter().enumerate() {
+            let table_frequencies = &mut frequencies[huffman_table_id(comp_id) as usize];
+            for zz_block in blocks.iter() {
+                block_symbols(
+                    zz_block,
+                    &mut dc_predictions[comp_id],
+                    |class, symbol, _, _| {
+                        table_frequencies[class as usize][symbol as usize] += 1;
+                    },
+                );
+            }
+        }
+    }
+
+    let mut tables = Vec::new();
+    for (id, table_frequencies) in frequencies.iter().enumerate() {
+        for class in [HUFFMAN_DC, HUFFMAN_AC] {
+            let (lengths, vals) = optimal_code_lengths(&table_frequencies[class as usize]);
+            tables.push(HuffmanTable::new(class, id as u8, &lengths, &vals));
+        }
+    }
+
+    tables
+}
+
+// Builds the number of codes of every length and the symbols sorted by code
+// length, following Annex K.2: codes are limited to 16 bits and a reserved
+// symbol keeps any symbol from getting the all-ones code
+fn optimal_code_lengths(frequencies: &[u32; 256]) -> ([u8; 16], Vec<u8>) {
+    let mut freq = [0u64; 257];
+    for (f, count) in freq.iter_mut().zip(frequencies.iter()) {
+        *f = *count as u64;
+    }
+    freq[256] = 1;
+
+    // merge the two least frequent trees until one is left, counting how deep
+    // every symbol ends up
+    let mut code_size = [0usize; 257];
+    let mut others: [Option<usize>; 257] = [None; 257];
+    loop {
+        let mut v1 = None;
+        let mut v2 = None;
+        for (i, f) in freq.iter().enumerate() {
+            if *f == 0 {
+                continue;
+            }
+            // on ties, the larger symbol is merged first
+            if v1.is_none_or(|v1: usize| *f <= freq[v1]) {
+                v2 = v1;
+                v1 = Some(i);
+            } else if v2.is_none_or(|v2: usize| *f <= freq[v2]) {
+                v2 = Some(i);
+            }
+        }
+        let (mut v1, mut v2) = match (v1, v2) {
+            (Some(v1), Some(v2)) => (v1, v2),
+            _ => break,
+        };
+
+        freq[v1] += freq[v2];
+        freq[v2] = 0;
+
+        code_size[v1] += 1;
+        while let Some(next) = others[v1] {
+            v1 = next;
+            code_size[v1] += 1;
+        }
+        others[v1] = Some(v2);
+
+        code_size[v2] += 1;
+        while let Some(next) = others[v2] {
+            v2 = next;
+            code_size[v2] += 1;
+        }
+    }
+
+    // skewed frequencies can make the tree deeper than the 32 levels of Annex K,
+    // so the histogram goes as deep as the longest code
+    let max_size = code_size.iter().copied().max().unwrap_or(0).max(16);
+    let mut bits = vec![0usize; max_size + 1];
+    for size in code_size.iter().filter(|size| **size > 0) {
+        bits[*size] += 1;
+    }
+
+    // move the codes longer than 16 bits up the tree
+    let mut i = max_size;
+    while i > 16 {
+        while bits[i] > 0 {
+            let mut j = i - 2;
+            while bits[j] == 0 {
+                j -= 1;
+            }
+            bits[i] -= 2;
+            bits[i - 1] += 1;
+            bits[j + 1] += 2;
+            bits[j] -= 1;
+        }
+        i -= 1;
+    }
+
+    // drop the reserved symbol, which has the longest code
+    let mut i = 16;
+    while bits[i] == 0 {
+        i -= 1;
+    }
+    bits[i] -= 1;
+
+    let mut lengths = [0; 16];
+    for (length, count) in lengths.iter_mut().zip(bits[1..=16].iter()) {
+        *length = *count as u8;
+    }
+
+    // symbols by increasing code size, the shorter codes going to the first ones
+    let mut vals = (0..256)
+        .filter(|symbol| code_size[*symbol] > 0)
+        .map(|symbol| symbol as u8)
+        .collect::<Vec<_>>();
+    vals.sort_by_key(|symbol| code_size[*symbol as usize]);
+
+    (lengths, vals)
+}
+
+// Baseline symbols hold DC differences of up to 11 bits and AC coefficients of
+// up to 10 bits, which the coefficients of 16-bit tables can exceed
+fn check_categories(mcus: &[MCU], restart_interval: u16) -> io::Result<()> {
+    let mut dc_predictions = vec![0; mcus.first().map_or(0, |mcu| mcu.zz_dct_coeff.len())];
+    for (mcu_index, mcu) in mcus.iter().enumerate() {
+        if starts_interval(mcu_index, restart_interval) {
+            dc_predictions.fill(0);
+        }
+        for (comp_id, blocks) in mcu.zz_dct_coeff.iter().enumerate() {
+            for zz_block in blocks.iter() {
+                let dc_difference = zz_block[0] as i64 - dc_predictions[comp_id] as i64;
+                dc_predictions[comp_id] = zz_block[0];
+                if dc_difference.unsigned_abs() > 2047
+                    || zz_block[1..]
+                        .iter()
+                        .any(|coeff| coeff.unsigned_abs() > 1023)
+                {
+                    return Err(invalid_input("coefficients too large for a baseline file"));
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Whether the MCU at `mcu_index` starts a new restart interval, after the first one
+fn starts_interval(mcu_index: usize, restart_interval: u16) -> bool {
+    restart_interval > 0 && mcu_index > 0 && mcu_index.is_multiple_of(restart_interval as usize)
+}
+
+// Huffman codes the blocks of every MCU
+fn encode_scan(
+    mcus: &[MCU],
+    huffman_tables: &[HuffmanTable],
+    restart_interval: u16,
+) -> io::Result<Vec<u8>> {
+    let table = |class: u8, id: u8| {
+        huffman_tables
+            .iter()
+            .find(|t| t.class == class && t.id == id)
+            .ok_or_else(|| invalid_input("missing huffman table"))
+    };
+
+    let mut writer = BitWriter::new();
+    let mut dc_predictions = vec![0; mcus.first().map_or(0, |mcu| mcu.zz_dct_coeff.len())];
+    for (mcu_index, mcu) in mcus.iter().enumerate() {
+        if starts_interval(mcu_index, restart_interval) {
+            let interval = mcu_index / restart_interval as usize - 1;
+            writer.write_marker(JFIF_RST0 + (interval % 8) as u8);
+            dc_predictions.fill(0);
+        }
+        for (comp_id, blocks) in mcu.zz_dct_coeff.iter().enumerate() {
+            let tables = [
+                table(HUFFMAN_DC, huffman_table_id(comp_id))?,
+                table(HUFFMAN_AC, huffman_table_id(comp_id))?,
+            ];
+
+            for zz_block in blocks.iter() {
+                let mut res = Ok(());
+                block_symbols(
+                    zz_block,
+                    &mut dc_predictions[comp_id],
+                    |class, symbol, bits, count| {
+                        if res.is_ok() {
+                            res = tables[class as usize].write_symbol(&mut writer, symbol);
+                            writer.write_bits(bits, count);
+                        }
+                    },
+                );
+                res?;
+            }
+        }
+    }
+
+    Ok(writer.into_bytes())
+}
+
+// Calls `emit` with the class, symbol and extra bits (and their count) of every
+// Huffman coded symbol of a block
+fn block_symbols<F: FnMut(u8, u8, u16, u8)>(
+    zz_block: &[i32; 64],
+    dc_prediction: &mut i32,
+    mut emit: F,
+) {
+    let (category, bits) = category_and_bits(zz_block[0] - *dc_prediction);
+    emit(HUFFMAN_DC, category, bits, category);
+    *dc_prediction = zz_block[0];
+
+    let mut zero_count = 0;
+    for coeff in zz_block[1..].iter() {
+        if *coeff == 0 {
+            zero_count += 1;
+            continue;
+        }
+        while zero_count > 15 {
+            emit(HUFFMAN_AC, HUFFMAN_ZRL, 0, 0);
+            zero_count -= 16;
+        }
+
+        let (category, bits) = category_and_bits(*coeff);
+        emit(HUFFMAN_AC, (zero_count << 4) | category, bits, category);
+        zero_count = 0;
+    }
+    if zero_count > 0 {
+        emit(HUFFMAN_AC, HUFFMAN_EOB, 0, 0);
+    }
+}
+
+fn write_app0<W: Write>(writer: &mut W) -> io::Result<()> {
+    let mut contents = b"JFIF\0".to_vec();
+    contents.extend_from_slice(&[1, 1]); // version 1.1
+    contents.push(0); // no density units, only the aspect ratio
+    contents.extend_from_slice(&[0, 1, 0, 1]); // 1:1 density
+    contents.extend_from_slice(&[0, 0]); // no thumbnail
+
+    write_segment(writer, JFIF_APP0, &contents)
+}
+
+fn write_dqt<W: Write>(writer: &mut W, tables: &[[u16; 64]]) -> io::Result<()> {
+    let mut contents = Vec::new();
+    for (id, table) in tables.iter().enumerate() {
+        if table.iter().all(|val| *val <= 0xFF) {
+            contents.push(id as u8);
+            contents.extend(table.iter().map(|val| *val as u8));
+        } else {
+            contents.push(0x10 | id as u8); // 16-bit precision
+            contents.extend(table.iter().flat_map(|val| val.to_be_bytes()));
+        }
+    }
+
+    write_segment(writer, JFIF_DQT, &contents)
+}
+
+// Baseline frames only allow 8-bit quantization tables, extended ones are used otherwise
+fn write_sof<W: Write>(
+    writer: &mut W,
+    width: usize,
+    height: usize,
+    components: &[Component],
+    quantization_tables: &[[u16; 64]],
+) -> io::Result<()> {
+    let mut contents = vec![8]; // sample precision
+    contents.extend_from_slice(&(height as u16).to_be_bytes());
+    contents.extend_from_slice(&(width as u16).to_be_bytes());
+    contents.push(components.len() as u8);
+    for component in components.iter() {
+        contents.push(component.id);
+        contents.push((component.horizontal_sampling << 4 | component.vertical_sampling) as u8);
+        contents.push(component.quantization_table);
+    }
+
+    let baseline = quantization_tables.iter().flatten().all(|val| *val <= 0xFF);
+    let marker = if baseline { JFIF_SOF0 } else { JFIF_SOF1 };
+    write_segment(writer, marker, &contents)
+}
+
+fn write_dht<W: Write>(writer: &mut W, tables: &[HuffmanTable]) -> io::Result<()> {
+    let mut contents = Vec::new();
+    for table in tables.iter() {
+        contents.push(table.class << 4 | table.id);
+        contents.extend_from_slice(&table.lengths);
+        contents.extend_from_slice(&table.vals);
+    }
+
+    write_segment(writer, JFIF_DHT, &contents)
+}
+
+fn write_sos<W: Write>(writer: &mut W, components: &[Component]) -> io::Result<()> {
+    let mut contents = vec![components.len() as u8];
+    for (comp_id, component) in components.iter().enumerate() {
+        let table_id = huffman_table_id(comp_id);
+        contents.push(component.id);
+        contents.push(table_id << 4 | table_id);
+    }
+    contents.extend_from_slice(&[0, 63, 0]); // spectral selection and approximation
+
+    write_segment(writer, JFIF_SOS, &contents)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bit_reader::BitReader;
+    use crate::coefficients::CoefficientOrder;
+    use crate::decoder::Decoder;
+    use crate::huffman_tree::{HuffmanResult, HuffmanTree};
+
+    // A gradient with some noise, so that the blocks hold many AC coefficients
+    fn noisy_image(width: usize, height: usize) -> Image {
+        let mut seed: u32 = 1;
+        let data = (0..width * height * 3)
+            .map(|i| {
+                seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+                let noise = (seed >> 16) % 48;
+                ((i / 3 % width * 3 + i / 3 / width * 2) as u32 + noise).min(255) as u8
+            })
+            .collect();
+
+        Image::from_vec(width, height, PixelFormat::Rgb8, data).unwrap()
+    }
+
+    // Writes every symbol of the table and reads them back through the decoder's tree
+    fn assert_decodes(table: &HuffmanTable) {
+        assert!(table.lengths.iter().map(|l| *l as usize).sum::<usize>() == table.vals.len());
+
+        let mut tree = HuffmanTree::new(table.class, table.id);
+        tree.build(&table.lengths, &table.vals).unwrap();
+
+        let mut writer = BitWriter::new();
+        for symbol in table.vals.iter() {
+            table.write_symbol(&mut writer, *symbol).unwrap();
+        }
+        let bytes = writer.into_bytes();
+        let mut reader = BitReader::new(&bytes, 0);
+        for symbol in table.vals.iter() {
+            let decoded = tree.decode(&mut reader).unwrap();
+            assert!(matches!(decoded, HuffmanResult::Some(val) if val == *symbol));
+        }
+    }
+
+    #[test]
+    fn optimized_tables_decode_and_shrink_the_file() {
+        let img = noisy_image(64, 48);
+        let mut encoder = Encoder::new(75);
+        let standard = encoder.encode(&img).unwrap();
+        encoder.set_optimize_huffman(true);
+        let optimized = encoder.encode(&img).unwrap();
+        assert!(optimized.len() < standard.len());
+
+        // the same coefficients come out of both files
+        let coefficients = |bytes: Vec<u8>| {
+            let coefficients = Decoder::from_vec(bytes)
+                .decode_coefficients(CoefficientOrder::Zigzag)
+                .unwrap();
+            coefficients
+                .components
+                .into_iter()
+                .map(|c| c.blocks)
+                .collect::<Vec<_>>()
+        };
+        assert!(coefficients(standard.clone()) == coefficients(optimized));
+
+        let mut decoder = Decoder::from_vec(standard);
+        decoder.decode().unwrap();
+        let tables = optimized_huffman_tables(decoder.mcus(), 3, 0);
+        assert_eq!(tables.len(), 4);
+        tables.iter().for_each(assert_decodes);
+    }
+
+    // Checks that the codes built for the first `count` symbols fit 16 bits,
+    // favor the frequent symbols and decode
+    fn assert_limited_lengths(frequencies: &[u32; 256], count: usize) {
+        let (lengths, vals) = optimal_code_lengths(frequencies);
+        assert_eq!(vals.len(), count);
+        assert!(lengths.iter().map(|l| *l as usize).sum::<usize>() == count);
+        // the longest codes fill the 16th bit, the all ones code staying unused
+        assert!(lengths[15] > 0);
+        let kraft = lengths
+            .iter()
+            .enumerate()
+            .map(|(i, l)| *l as f64 / (1 << (i + 1)) as f64)
+            .sum::<f64>();
+        assert!(kraft < 1.0);
+
+        // the more frequent a symbol, the shorter its code
+        let table = HuffmanTable::new(HUFFMAN_AC, 0, &lengths, &vals);
+        for (i, (_, length)) in table.codes.iter().enumerate().take(count) {
+            for (j, (_, other_length)) in table.codes.iter().enumerate().take(count) {
+                assert!(frequencies[i] <= frequencies[j] || length <= other_length);
+            }
+        }
+
+        assert_decodes(&table);
+    }
+
+    #[test]
+    fn limits_optimized_codes_to_16_bits() {
+        // doubling frequencies give a Huffman tree as deep as there are symbols
+        let mut frequencies = [0; 256];
+        for (i, f) in frequencies.iter_mut().take(24).enumerate() {
+            *f = 1 << i;
+        }
+
+        assert_limited_lengths(&frequencies, 24);
+    }
+
+    #[test]
+    fn limits_codes_deeper_than_32_bits() {
+        // Fibonacci frequencies put one symbol on every level of a tree 45 deep
+        let mut frequencies = [0; 256];
+        let (mut a, mut b) = (1u64, 2);
+        for f in frequencies.iter_mut().take(45) {
+            *f = a as u32;
+            (a, b) = (b, a + b);
+        }
+
+        assert_limited_lengths(&frequencies, 45);
+    }
+
+    #[test]
+    fn rejects_coefficients_outside_baseline_categories() {
+        let component = Component {
+            id: 1,
+            horizontal_sampling: 1,
+            vertical_sampling: 1,
+            quantization_table: 0,
+        };
+        let encode = |blocks: [[i32; 64]; 2]| {
+            let mcus = blocks
+                .iter()
+                .enumerate()
+                .map(|(order, block)| MCU::new(order, vec![vec![*block]], vec![(1, 1)]).unwrap())
+                .collect::<Vec<_>>();
+            let mut bytes = Vec::new();
+            let res =
+                Encoder::new(90).encode_mcus(&mut bytes, 16, 8, &[component], &[[1; 64]], &mcus);
+            (res, bytes)
+        };
+
+        let mut blocks = [[0; 64]; 2];
+        blocks[0][0] = 2047;
+        blocks[1][0] = -2047;
+        blocks[1][63] = -1023;
+        assert!(encode(blocks).0.is_err());
+
+        // a DC difference of 11 bits and AC coefficients of 10 bits still fit
+        blocks[1][0] = 0;
+        assert!(encode(blocks).0.is_ok());
+
+        blocks[0][5] = 1024;
+        let (res, bytes) = encode(blocks);
+        assert_eq!(res.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        assert!(bytes.is_empty());
+
+        blocks[0][5] = i32::MIN;
+        assert!(encode(blocks).0.is_err());
+    }
+}
@@ -0,0 +1,104 @@
+use std::fmt;
+use std::io;
+
+#[derive(Debug)]
+pub enum DecodeErrorKind {
+    Io(io::Error),
+    Truncated,                    // Data ended in the middle of a segment or scan
+    NotJpeg,                      // The SOI marker is missing
+    BadMarker(u8),                // A marker that is not valid at this position
+    MissingJfif,                  // APP0 segment without the 'JFIF\0' identifier
+    InvalidSegment(&'static str), // A segment whose contents are malformed
+    Unsupported(&'static str),    // A valid JPEG feature the decoder does not handle
+    InvalidHuffmanTable,          // A DHT segment whose code lengths do not fit
+    InvalidHuffmanCode,           // A bit sequence that matches no Huffman code
+    MissingHuffmanTable { class: u8, id: u8 }, // A scan refers to an undefined table
+    MissingQuantizationTable(u8), // A scanned component uses an undefined table
+    MissingFrame,                 // A scan was found before any SOF segment
+    InvalidRegion,                // A requested region does not overlap the image
+    BufferTooSmall { needed: usize }, // An output buffer cannot hold the decoded image
+    ImageTooLarge { pixels: usize }, // The frame exceeds the pixel limit of the decoder
+}
+
+#[derive(Debug)]
+pub struct DecodeError {
+    kind: DecodeErrorKind,
+    offset: usize,
+}
+
+impl DecodeError {
+    pub fn new(kind: DecodeErrorKind, offset: usize) -> Self {
+        Self { kind, offset }
+    }
+
+    pub fn kind(&self) -> &DecodeErrorKind {
+        &self.kind
+    }
+
+    // Byte offset in the input where the error was detected
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+}
+
+impl fmt::Display for DecodeErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeErrorKind::Io(err) => write!(f, "i/o error: {}", err),
+            DecodeErrorKind::Truncated => write!(f, "image ended unexpectedly"),
+            DecodeErrorKind::NotJpeg => write!(f, "data is not a JPG image"),
+            DecodeErrorKind::BadMarker(marker) => write!(f, "unexpected marker {:02X}", marker),
+            DecodeErrorKind::MissingJfif => {
+                write!(f, "APP0 segment does not present the 'JFIF\\0' string")
+            }
+            DecodeErrorKind::InvalidSegment(reason) => write!(f, "invalid segment: {}", reason),
+            DecodeErrorKind::Unsupported(feature) => write!(f, "unsupported: {}", feature),
+            DecodeErrorKind::InvalidHuffmanTable => {
+                write!(f, "huffman code lengths exceed the code space")
+            }
+            DecodeErrorKind::InvalidHuffmanCode => write!(f, "invalid huffman code"),
+            DecodeErrorKind::MissingHuffmanTable { class, id } => write!(
+                f,
+                "missing huffman table {} ({})",
+                id,
+                if *class == 0 { "dc" } else { "ac" }
+            ),
+            DecodeErrorKind::MissingQuantizationTable(id) => {
+                write!(f, "missing quantization table {}", id)
+            }
+            DecodeErrorKind::MissingFrame => write!(f, "scan found before start of frame"),
+            DecodeErrorKind::InvalidRegion => write!(f, "region lies outside the image"),
+            DecodeErrorKind::BufferTooSmall { needed } => {
+                write!(
+                    f,
+                    "output buffer is smaller than the {} bytes needed",
+                    needed
+                )
+            }
+            DecodeErrorKind::ImageTooLarge { pixels } => {
+                write!(f, "image of {} pixels exceeds the decoder limit", pixels)
+            }
+        }
+    }
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[E] - {} (at byte {})", self.kind, self.offset)
+    }
+}
+
+impl std::error::Error for DecodeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match &self.kind {
+            DecodeErrorKind::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for DecodeError {
+    fn from(err: io::Error) -> Self {
+        Self::new(DecodeErrorKind::Io(err), 0)
+    }
+}
@@ -1,164 +1,118 @@
-pub struct HuffmanTree {
-    nodes: Vec<Node>,
+use crate::bit_reader::BitReader;
+use crate::error::{DecodeError, DecodeErrorKind};
+
+const LOOKUP_BITS: u8 = 9; // codes up to this length are decoded with a single lookup
 
-    pub table_type: u8, // 0 for luminance, 1 for chrominance
+// Decoding tables of the canonical codes defined by a DHT segment
+pub struct HuffmanTree {
+    pub table_type: u8, // 0 for DC, 1 for AC
     pub table_number: u8,
 
-    depth: usize,
+    lookup: Vec<(u8, u8)>, // (code length, symbol) for every LOOKUP_BITS prefix, 0 when longer
+    max_code: [i32; 17],   // largest code of every length, -1 when there is none
+    val_offset: [i32; 17], // index in `vals` of the codes of every length, minus the first code
+    vals: Vec<u8>,
 }
 
-type NodeIndex = usize;
-
 impl HuffmanTree {
     pub fn new(table_type: u8, table_number: u8) -> Self {
         Self {
-            nodes: Vec::new(),
             table_type,
             table_number,
-            depth: 0,
+            lookup: Vec::new(),
+            max_code: [-1; 17],
+            val_offset: [0; 17],
+            vals: Vec::new(),
         }
     }
 
-    pub fn build(&mut self, lengths: &Vec<u8>, vals: &Vec<u8>) {
-        self.nodes = Vec::new();
-
-        self.depth = lengths.len();
+    pub fn build(&mut self, lengths: &[u8], vals: &[u8]) -> Result<(), DecodeErrorKind> {
+        validate_code_lengths(lengths, vals.len())?;
+        self.build_lookup(lengths, vals);
 
-        let root = Node::new(None, None, None, 0);
-        self.nodes.push(root);
-
-        self.insert_children(0);
+        Ok(())
+    }
 
-        let mut leftmost_node_index: NodeIndex = 1;
+    // Assigns the canonical codes and fills the tables used by `decode`
+    fn build_lookup(&mut self, lengths: &[u8], vals: &[u8]) {
+        self.vals = vals.to_vec();
+        self.lookup = vec![(0, 0); 1 << LOOKUP_BITS];
+        self.max_code = [-1; 17];
+        self.val_offset = [0; 17];
 
-        let mut current_value_index = 0;
+        let mut code: i32 = 0;
+        let mut val_index: i32 = 0;
+        for (i, l) in lengths.iter().enumerate() {
+            let length = i as u8 + 1;
+            self.val_offset[length as usize] = val_index - code;
 
-        for l in lengths {
-            let mut current_node_index = leftmost_node_index;
             for _ in 0..*l {
-                self.nodes[current_node_index].code = vals[current_value_index];
-                current_value_index += 1;
-                current_node_index = self
-                    .right_node_level(current_node_index)
-                    .expect("[E] - length exceeded current's level space");
-            }
-
-            let leftmost_parent_index = current_node_index;
-
-            while let Some(next_node_index) = self.right_node_level(current_node_index) {
-                self.insert_children(current_node_index);
-                current_node_index = next_node_index;
+                if length <= LOOKUP_BITS {
+                    let shift = LOOKUP_BITS - length;
+                    let first = (code as usize) << shift;
+                    for entry in self.lookup[first..first + (1 << shift)].iter_mut() {
+                        *entry = (length, vals[val_index as usize]);
+                    }
+                }
+                code += 1;
+                val_index += 1;
             }
-            self.insert_children(current_node_index);
-
-            leftmost_node_index = self.nodes[leftmost_parent_index].left_child.unwrap();
-        }
-    }
-
-    fn insert_children(&mut self, parent_node: NodeIndex) {
-        let left_child = Node::new(Some(parent_node), None, None, 0);
-        self.nodes.push(left_child);
-        self.nodes[parent_node].left_child = Some(self.nodes.len() - 1);
-
-        let right_child = Node::new(Some(parent_node), None, None, 0);
-        self.nodes.push(right_child);
-        self.nodes[parent_node].right_child = Some(self.nodes.len() - 1);
-    }
-
-    fn right_node_level(&self, node: NodeIndex) -> Option<NodeIndex> {
-        let parent = self.nodes[node].parent_index;
-
-        let parent = match parent {
-            Some(parent) => parent,
-            None => return None,
-        };
-
-        let right_sibling = self.nodes[parent].right_child.unwrap();
 
-        if right_sibling == node {
-            match self.right_node_level(parent) {
-                Some(parent_right_sibling) => {
-                    Some(self.nodes[parent_right_sibling].left_child.unwrap())
-                }
-                None => None,
+            if *l > 0 {
+                self.max_code[length as usize] = code - 1;
             }
-        } else {
-            Some(right_sibling)
+            code <<= 1;
         }
     }
 
-    pub fn try_decode(&self, bits: &[bool]) -> HuffmanResult {
-        let mut node_index = 0;
+    // Decodes the next symbol from the reader, using the lookup table for short
+    // codes and the canonical code ranges for the longer ones
+    pub fn decode(&self, reader: &mut BitReader) -> Result<HuffmanResult, DecodeError> {
+        let bits = reader.peek_bits(16);
 
-        for bit in bits.iter() {
-            node_index = if *bit {
-                self.nodes[node_index].right_child.unwrap()
-            } else {
-                self.nodes[node_index].left_child.unwrap()
-            };
+        let (length, symbol) = self.lookup[(bits >> (16 - LOOKUP_BITS)) as usize];
+        if length > 0 {
+            reader.consume_bits(length)?;
+            return Ok(HuffmanResult::Some(symbol));
         }
 
-        if self.nodes[node_index].is_leaf() {
-            if bits.len() > self.depth || self.nodes[node_index].code == 0 {
-                return HuffmanResult::EOB;
+        for length in LOOKUP_BITS + 1..=16 {
+            let code = (bits >> (16 - length)) as i32;
+            if code <= self.max_code[length as usize] {
+                reader.consume_bits(length)?;
+                let symbol = self.vals[(self.val_offset[length as usize] + code) as usize];
+                return Ok(HuffmanResult::Some(symbol));
             }
-            return HuffmanResult::Some(self.nodes[node_index].code);
-        } else {
-            return HuffmanResult::None;
         }
-    }
-
-    pub fn print(&self) {
-        let mut stack = Vec::new();
 
-        stack.push((0, String::from("")));
-        println!("depth: {}", self.depth);
-
-        while let Some((node, node_code)) = stack.pop() {
-            if self.nodes[node].is_leaf() {
-                println!("{:02X} = {}", self.nodes[node].code, node_code);
-            }
-            if let Some(left_node) = self.nodes[node].left_child {
-                stack.push((left_node, format!("{}0", node_code)));
-            }
-            if let Some(right_node) = self.nodes[node].right_child {
-                stack.push((right_node, format!("{}1", node_code)));
-            }
-        }
+        Ok(HuffmanResult::Invalid)
     }
 }
 
-pub enum HuffmanResult {
-    Some(u8),
-    None,
-    EOB,
-}
-
-#[derive(PartialEq, Eq)]
-struct Node {
-    parent_index: Option<NodeIndex>,
-
-    left_child: Option<NodeIndex>,
-    right_child: Option<NodeIndex>,
-    code: u8,
-}
+// Checks that `lengths[i]` codes of length i + 1 fit in the 16-bit code space,
+// leaving out the all-ones codes that JPEG reserves
+fn validate_code_lengths(lengths: &[u8], val_count: usize) -> Result<(), DecodeErrorKind> {
+    if lengths.len() > 16 || val_count > 256 {
+        return Err(DecodeErrorKind::InvalidHuffmanTable);
+    }
+    if lengths.iter().map(|l| *l as usize).sum::<usize>() != val_count {
+        return Err(DecodeErrorKind::InvalidHuffmanTable);
+    }
 
-impl Node {
-    fn new(
-        parent_index: Option<NodeIndex>,
-        left_child: Option<NodeIndex>,
-        right_child: Option<NodeIndex>,
-        code: u8,
-    ) -> Self {
-        Self {
-            parent_index,
-            left_child,
-            right_child,
-            code,
+    let mut code: u32 = 0;
+    for (i, l) in lengths.iter().enumerate() {
+        code += *l as u32;
+        if *l > 0 && code >= 1 << (i + 1) {
+            return Err(DecodeErrorKind::InvalidHuffmanTable);
         }
+        code <<= 1;
     }
 
-    fn is_leaf(&self) -> bool {
-        self.left_child.is_none() && self.right_child.is_none()
-    }
+    Ok(())
+}
+
+// Symbols are returned as found in the table, the scan decoder gives them a meaning
+pub enum HuffmanResult {
+    Some(u8), // a complete code and its symbol
+    Invalid,
 }
@@ -0,0 +1,515 @@
+use std::f64::consts::PI;
+use std::f64::consts::SQRT_2;
+use std::sync::OnceLock;
+
+// Algorithm used to transform the dequantized coefficients of a block back
+// into samples. Every method returns samples centered around 0.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum IdctMethod {
+    // direct evaluation of the 2-D formula, very slow
+    Reference,
+    // rows then columns with precomputed cosines
+    #[default]
+    SeparableFloat,
+    // Arai, Agui and Nakajima's algorithm, as libjpeg's JDCT_FLOAT
+    FastFloat,
+    // Loeffler, Ligtenberg and Moschytz's algorithm, as libjpeg's JDCT_ISLOW
+    IntegerSlow,
+}
+
+// Size of the decoded image relative to the original one
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Scale {
+    #[default]
+    Full,
+    Half,
+    Quarter,
+    Eighth,
+}
+
+impl Scale {
+    // Side of the square of samples every 8x8 block is reduced to
+    pub fn block_size(&self) -> usize {
+        match self {
+            Scale::Full => 8,
+            Scale::Half => 4,
+            Scale::Quarter => 2,
+            Scale::Eighth => 1,
+        }
+    }
+
+    // Size of an image of `size` pixels once scaled, rounded up
+    pub fn scaled_size(&self, size: usize) -> usize {
+        (size * self.block_size()).div_ceil(8)
+    }
+}
+
+// Transforms a block of dequantized coefficients given in natural (row by row) order
+pub fn idct(method: IdctMethod, coefficients: &[i32; 64]) -> [[f64; 8]; 8] {
+    match method {
+        IdctMethod::Reference => idct_reference(coefficients),
+        IdctMethod::SeparableFloat => idct_separable(coefficients),
+        IdctMethod::FastFloat => idct_fast_float(coefficients),
+        IdctMethod::IntegerSlow => idct_integer_slow(coefficients),
+    }
+}
+
+pub fn idct_reference(coefficients: &[i32; 64]) -> [[f64; 8]; 8] {
+    // C(u) C(v) / 4 * cos((2x + 1)uπ / 16) * cos((2y + 1)vπ / 16) for every term
+    let cosines = cosine_table();
+
+    let mut block = [[0.0; 8]; 8];
+    for (y, row) in block.iter_mut().enumerate() {
+        for (x, val) in row.iter_mut().enumerate() {
+            let mut sum: f64 = 0.0;
+            for (i, coeff) in coefficients.iter().enumerate() {
+                let (v, u) = (i / 8, i % 8);
+                sum += *coeff as f64 * cosines[x][u] * cosines[y][v];
+            }
+            *val = sum;
+        }
+    }
+
+    block
+}
+
+// cos((2x + 1)uπ / 16) scaled by C(u) / 2, indexed by sample x and frequency u
+pub(crate) fn cosine_table() -> &'static [[f64; 8]; 8] {
+    static TABLE: OnceLock<[[f64; 8]; 8]> = OnceLock::new();
+    TABLE.get_or_init(|| {
+        let mut table = [[0.0; 8]; 8];
+        for (x, row) in table.iter_mut().enumerate() {
+            for (u, val) in row.iter_mut().enumerate() {
+                let cu = if u == 0 { 1.0 / SQRT_2 } else { 1.0 };
+                *val = cu / 2.0 * f64::cos(((2 * x + 1) * u) as f64 * PI / 16.0);
+            }
+        }
+        table
+    })
+}
+
+fn idct_separable(coefficients: &[i32; 64]) -> [[f64; 8]; 8] {
+    let cosines = cosine_table();
+
+    // 1-D transform of every row of coefficients
+    let mut temp = [[0.0; 8]; 8];
+    for (v, row) in temp.iter_mut().enumerate() {
+        let coeff_row = &coefficients[v * 8..v * 8 + 8];
+        for (x, val) in row.iter_mut().enumerate() {
+            *val = coeff_row
+                .iter()
+                .zip(cosines[x].iter())
+                .map(|(coeff, cos)| *coeff as f64 * cos)
+                .sum();
+        }
+    }
+
+    // 1-D transform of every column of the result
+    let mut block = [[0.0; 8]; 8];
+    for (y, row) in block.iter_mut().enumerate() {
+        for (x, val) in row.iter_mut().enumerate() {
+            *val = temp
+                .iter()
+                .zip(cosines[y].iter())
+                .map(|(temp_row, cos)| temp_row[x] * cos)
+                .sum();
+        }
+    }
+
+    block
+}
+
+// cos((2x + 1)uπ / 2n) scaled by C(u) / 2 for n = 2 and n = 4, indexed by
+// n / 4, then sample x and frequency u
+fn reduced_cosine_tables() -> &'static [[[f64; 4]; 4]; 2] {
+    static TABLES: OnceLock<[[[f64; 4]; 4]; 2]> = OnceLock::new();
+    TABLES.get_or_init(|| {
+        let mut tables = [[[0.0; 4]; 4]; 2];
+        for (i, table) in tables.iter_mut().enumerate() {
+            let size = 2 << i;
+            for (x, row) in table.iter_mut().enumerate().take(size) {
+                for (u, val) in row.iter_mut().enumerate().take(size) {
+                    let cu = if u == 0 { 1.0 / SQRT_2 } else { 1.0 };
+                    *val = cu / 2.0 * f64::cos(((2 * x + 1) * u) as f64 * PI / (2 * size) as f64);
+                }
+            }
+        }
+        tables
+    })
+}
+
+// Transforms the lowest `size` x `size` frequencies of a block (in natural
+// order) into as many samples, which approximate the block shrunk by 8 / `size`.
+// The samples are returned in the top-left corner of the result.
+pub fn idct_reduced(coefficients: &[i32; 64], size: usize) -> [[f64; 8]; 8] {
+    let mut block = [[0.0; 8]; 8];
+    if size >= 8 {
+        return idct_separable(coefficients);
+    }
+    if size <= 1 {
+        block[0][0] = coefficients[0] as f64 / 8.0;
+        return block;
+    }
+
+    let cosines = &reduced_cosine_tables()[size / 4];
+
+    // 1-D transform of the low frequencies of every row, then of every column
+    let mut temp = [[0.0; 4]; 4];
+    for (v, row) in temp.iter_mut().enumerate().take(size) {
+        for (x, val) in row.iter_mut().enumerate().take(size) {
+            *val = (0..size)
+                .map(|u| coefficients[v * 8 + u] as f64 * cosines[x][u])
+                .sum();
+        }
+    }
+    for (y, row) in block.iter_mut().enumerate().take(size) {
+        for (x, val) in row.iter_mut().enumerate().take(size) {
+            *val = (0..size).map(|v| temp[v][x] * cosines[y][v]).sum();
+        }
+    }
+
+    block
+}
+
+// Scale factors folded into the coefficients before the AAN transform:
+// cos(kπ / 16) * sqrt(2) for k > 0, 1 for k = 0
+fn aan_scale_factors() -> &'static [f64; 8] {
+    static FACTORS: OnceLock<[f64; 8]> = OnceLock::new();
+    FACTORS.get_or_init(|| {
+        let mut factors = [1.0; 8];
+        for (k, factor) in factors.iter_mut().enumerate().skip(1) {
+            *factor = f64::cos(k as f64 * PI / 16.0) * SQRT_2;
+        }
+        factors
+    })
+}
+
+// One 8-point AAN transform over `data[offset + i * stride]`
+fn aan_1d(data: &mut [f64; 64], offset: usize, stride: usize) {
+    let at = |i: usize| offset + i * stride;
+
+    // even part
+    let tmp0 = data[at(0)];
+    let tmp1 = data[at(2)];
+    let tmp2 = data[at(4)];
+    let tmp3 = data[at(6)];
+
+    let tmp10 = tmp0 + tmp2;
+    let tmp11 = tmp0 - tmp2;
+    let tmp13 = tmp1 + tmp3;
+    let tmp12 = (tmp1 - tmp3) * SQRT_2 - tmp13;
+
+    let tmp0 = tmp10 + tmp13;
+    let tmp3 = tmp10 - tmp13;
+    let tmp1 = tmp11 + tmp12;
+    let tmp2 = tmp11 - tmp12;
+
+    // odd part
+    let tmp4 = data[at(1)];
+    let tmp5 = data[at(3)];
+    let tmp6 = data[at(5)];
+    let tmp7 = data[at(7)];
+
+    let z13 = tmp6 + tmp5;
+    let z10 = tmp6 - tmp5;
+    let z11 = tmp4 + tmp7;
+    let z12 = tmp4 - tmp7;
+
+    let tmp7 = z11 + z13;
+    let tmp11 = (z11 - z13) * SQRT_2;
+
+    let z5 = (z10 + z12) * 1.847759065;
+    let tmp10 = z5 - z12 * 1.082392200;
+    let tmp12 = z5 - z10 * 2.613125930;
+
+    let tmp6 = tmp12 - tmp7;
+    let tmp5 = tmp11 - tmp6;
+    let tmp4 = tmp10 - tmp5;
+
+    data[at(0)] = tmp0 + tmp7;
+    data[at(7)] = tmp0 - tmp7;
+    data[at(1)] = tmp1 + tmp6;
+    data[at(6)] = tmp1 - tmp6;
+    data[at(2)] = tmp2 + tmp5;
+    data[at(5)] = tmp2 - tmp5;
+    data[at(3)] = tmp3 + tmp4;
+    data[at(4)] = tmp3 - tmp4;
+}
+
+fn idct_fast_float(coefficients: &[i32; 64]) -> [[f64; 8]; 8] {
+    let factors = aan_scale_factors();
+
+    let mut data = [0.0; 64];
+    for (i, val) in data.iter_mut().enumerate() {
+        *val = coefficients[i] as f64 * factors[i / 8] * factors[i % 8] / 8.0;
+    }
+    for column in 0..8 {
+        aan_1d(&mut data, column, 8);
+    }
+    for row in 0..8 {
+        aan_1d(&mut data, row * 8, 1);
+    }
+
+    let mut block = [[0.0; 8]; 8];
+    for (i, val) in data.iter().enumerate() {
+        block[i / 8][i % 8] = *val;
+    }
+
+    block
+}
+
+// Fixed point constants of the integer transform, scaled by 2^CONST_BITS
+const CONST_BITS: u32 = 13;
+const PASS1_BITS: u32 = 2;
+
+const FIX_0_298631336: i64 = 2446;
+const FIX_0_390180644: i64 = 3196;
+const FIX_0_541196100: i64 = 4433;
+const FIX_0_765366865: i64 = 6270;
+const FIX_0_899976223: i64 = 7373;
+const FIX_1_175875602: i64 = 9633;
+const FIX_1_501321110: i64 = 12299;
+const FIX_1_847759065: i64 = 15137;
+const FIX_1_961570560: i64 = 16069;
+const FIX_2_053119869: i64 = 16819;
+const FIX_2_562915447: i64 = 20995;
+const FIX_3_072711026: i64 = 25172;
+
+// Divides by 2^bits, rounding to the nearest integer
+fn descale(x: i64, bits: u32) -> i64 {
+    (x + (1 << (bits - 1))) >> bits
+}
+
+// One 8-point integer transform over `data[offset + i * stride]`, with the
+// outputs descaled by `out_bits`
+fn islow_1d(data: &mut [i64; 64], offset: usize, stride: usize, out_bits: u32) {
+    let at = |i: usize| offset + i * stride;
+
+    // even part
+    let z2 = data[at(2)];
+    let z3 = data[at(6)];
+
+    let z1 = (z2 + z3) * FIX_0_541196100;
+    let tmp2 = z1 - z3 * FIX_1_847759065;
+    let tmp3 = z1 + z2 * FIX_0_765366865;
+
+    let z2 = data[at(0)];
+    let z3 = data[at(4)];
+
+    let tmp0 = (z2 + z3) << CONST_BITS;
+    let tmp1 = (z2 - z3) << CONST_BITS;
+
+    let tmp10 = tmp0 + tmp3;
+    let tmp13 = tmp0 - tmp3;
+    let tmp11 = tmp1 + tmp2;
+    let tmp12 = tmp1 - tmp2;
+
+    // odd part
+    let mut tmp0 = data[at(7)];
+    let mut tmp1 = data[at(5)];
+    let mut tmp2 = data[at(3)];
+    let mut tmp3 = data[at(1)];
+
+    let mut z1 = tmp0 + tmp3;
+    let mut z2 = tmp1 + tmp2;
+    let mut z3 = tmp0 + tmp2;
+    let mut z4 = tmp1 + tmp3;
+    let z5 = (z3 + z4) * FIX_1_175875602;
+
+    tmp0 *= FIX_0_298631336;
+    tmp1 *= FIX_2_053119869;
+    tmp2 *= FIX_3_072711026;
+    tmp3 *= FIX_1_501321110;
+    z1 *= -FIX_0_899976223;
+    z2 *= -FIX_2_562915447;
+    z3 *= -FIX_1_961570560;
+    z4 *= -FIX_0_390180644;
+
+    z3 += z5;
+    z4 += z5;
+
+    tmp0 += z1 + z3;
+    tmp1 += z2 + z4;
+    tmp2 += z2 + z3;
+    tmp3 += z1 + z4;
+
+    data[at(0)] = descale(tmp10 + tmp3, out_bits);
+    data[at(7)] = descale(tmp10 - tmp3, out_bits);
+    data[at(1)] = descale(tmp11 + tmp2, out_bits);
+    data[at(6)] = descale(tmp11 - tmp2, out_bits);
+    data[at(2)] = descale(tmp12 + tmp1, out_bits);
+    data[at(5)] = descale(tmp12 - tmp1, out_bits);
+    data[at(3)] = descale(tmp13 + tmp0, out_bits);
+    data[at(4)] = descale(tmp13 - tmp0, out_bits);
+}
+
+fn idct_integer_slow(coefficients: &[i32; 64]) -> [[f64; 8]; 8] {
+    let mut data = [0; 64];
+    for (val, coeff) in data.iter_mut().zip(coefficients.iter()) {
+        *val = *coeff as i64;
+    }
+
+    // the columns keep PASS1_BITS of extra precision for the rows
+    for column in 0..8 {
+        islow_1d(&mut data, column, 8, CONST_BITS - PASS1_BITS);
+    }
+    for row in 0..8 {
+        islow_1d(&mut data, row * 8, 1, CONST_BITS + PASS1_BITS + 3);
+    }
+
+    let mut block = [[0.0; 8]; 8];
+    for (i, val) in data.iter().enumerate() {
+        block[i / 8][i % 8] = *val as f64;
+    }
+
+    block
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BLOCKS: usize = 10000;
+    // sample ranges of the IEEE 1180 test, each also run with negated samples
+    const RANGES: [(i32, i32); 3] = [(256, 255), (5, 5), (300, 300)];
+
+    // Coefficients of a block and the rounded output of the reference IDCT
+    type Case = ([i32; 64], [i32; 64]);
+
+    // Pseudo-random generator of the IEEE 1180 test, returning integers in [-low, high]
+    struct Ieee1180Random(u32);
+
+    impl Ieee1180Random {
+        fn next(&mut self, low: i32, high: i32) -> i32 {
+            self.0 = self.0.wrapping_mul(1103515245).wrapping_add(12345);
+            let x = (self.0 & 0x7ffffffe) as f64 / 0x7fffffff as f64;
+            (x * (low + high + 1) as f64) as i32 - low
+        }
+    }
+
+    // Double precision forward DCT, rounded and clipped to 12 bits
+    fn fdct(samples: &[i32; 64]) -> [i32; 64] {
+        let cosines = cosine_table();
+
+        let mut temp = [[0.0; 8]; 8];
+        for (y, row) in temp.iter_mut().enumerate() {
+            for (u, val) in row.iter_mut().enumerate() {
+                *val = (0..8)
+                    .map(|x| samples[y * 8 + x] as f64 * cosines[x][u])
+                    .sum();
+            }
+        }
+
+        let mut coefficients = [0; 64];
+        for (i, coeff) in coefficients.iter_mut().enumerate() {
+            let (v, u) = (i / 8, i % 8);
+            let sum = (0..8).map(|y| temp[y][u] * cosines[y][v]).sum::<f64>();
+            *coeff = (sum.round() as i32).clamp(-2048, 2047);
+        }
+
+        coefficients
+    }
+
+    fn round_and_clip(block: &[[f64; 8]; 8]) -> [i32; 64] {
+        let mut samples = [0; 64];
+        for (i, val) in samples.iter_mut().enumerate() {
+            *val = (block[i / 8][i % 8].round() as i32).clamp(-256, 255);
+        }
+
+        samples
+    }
+
+    // Random blocks of every run, shared by the tests of every method
+    fn ieee_1180_runs() -> &'static [Vec<Case>] {
+        static RUNS: OnceLock<Vec<Vec<Case>>> = OnceLock::new();
+        RUNS.get_or_init(|| {
+            let mut runs = Vec::new();
+            for (low, high) in RANGES.iter() {
+                for sign in [1, -1].iter() {
+                    let mut random = Ieee1180Random(1);
+                    let run = (0..BLOCKS)
+                        .map(|_| {
+                            let mut samples = [0; 64];
+                            for val in samples.iter_mut() {
+                                *val = random.next(*low, *high) * sign;
+                            }
+                            let coefficients = fdct(&samples);
+                            (coefficients, round_and_clip(&idct_reference(&coefficients)))
+                        })
+                        .collect();
+                    runs.push(run);
+                }
+            }
+            runs
+        })
+    }
+
+    // Checks the IEEE 1180 error bounds of `method` over every run, along with
+    // the zero output of an all zero block
+    fn check_ieee_1180(method: IdctMethod) {
+        for (run_id, run) in ieee_1180_runs().iter().enumerate() {
+            let mut errors = [0i64; 64];
+            let mut squared_errors = [0i64; 64];
+            for (coefficients, expected) in run.iter() {
+                let actual = round_and_clip(&idct(method, coefficients));
+                for i in 0..64 {
+                    let err = (actual[i] - expected[i]) as i64;
+                    assert!(err.abs() <= 1, "run {}: peak error of {}", run_id, err);
+                    errors[i] += err;
+                    squared_errors[i] += err * err;
+                }
+            }
+
+            let blocks = BLOCKS as f64;
+            for i in 0..64 {
+                let mean_error = errors[i] as f64 / blocks;
+                let mean_squared_error = squared_errors[i] as f64 / blocks;
+                assert!(
+                    mean_error.abs() <= 0.015,
+                    "run {}: mean error {} at {}",
+                    run_id,
+                    mean_error,
+                    i
+                );
+                assert!(
+                    mean_squared_error <= 0.06,
+                    "run {}: mean squared error {} at {}",
+                    run_id,
+                    mean_squared_error,
+                    i
+                );
+            }
+            let overall_error = errors.iter().sum::<i64>() as f64 / (blocks * 64.0);
+            let overall_squared_error = squared_errors.iter().sum::<i64>() as f64 / (blocks * 64.0);
+            assert!(
+                overall_error.abs() <= 0.0015,
+                "run {}: overall mean error {}",
+                run_id,
+                overall_error
+            );
+            assert!(
+                overall_squared_error <= 0.02,
+                "run {}: overall mean squared error {}",
+                run_id,
+                overall_squared_error
+            );
+        }
+
+        assert_eq!(round_and_clip(&idct(method, &[0; 64])), [0; 64]);
+    }
+
+    #[test]
+    fn separable_float_meets_ieee_1180() {
+        check_ieee_1180(IdctMethod::SeparableFloat);
+    }
+
+    #[test]
+    fn fast_float_meets_ieee_1180() {
+        check_ieee_1180(IdctMethod::FastFloat);
+    }
+
+    #[test]
+    fn integer_slow_meets_ieee_1180() {
+        check_ieee_1180(IdctMethod::IntegerSlow);
+    }
+}
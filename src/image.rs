@@ -1,67 +1,274 @@
-use crate::mcu::MCU;
+use crate::mcu::{ycbcr_to_rgb, MCU};
+use crate::pnm;
 use std::fs::File;
 use std::io::prelude::*;
 use std::io::BufWriter;
 
+// Memory layout of the pixels of an image, one byte per sample
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PixelFormat {
+    #[default]
+    Rgb8,
+    // opaque, the alpha sample is always 255
+    Rgba8,
+    Bgr8,
+    Bgra8,
+    // the luma of color images is their Y component
+    Gray8,
+    // full resolution Y, Cb and Cr planes, one after the other
+    YCbCrPlanar,
+}
+
+impl PixelFormat {
+    // Samples stored for every pixel
+    pub fn channels(&self) -> usize {
+        match self {
+            PixelFormat::Rgb8 | PixelFormat::Bgr8 | PixelFormat::YCbCrPlanar => 3,
+            PixelFormat::Rgba8 | PixelFormat::Bgra8 => 4,
+            PixelFormat::Gray8 => 1,
+        }
+    }
+
+    // Bytes of a row of `width` pixels, or of a row of one plane when planar
+    pub fn stride(&self, width: usize) -> usize {
+        match self {
+            PixelFormat::YCbCrPlanar => width,
+            _ => width * self.channels(),
+        }
+    }
+
+    // Bytes needed by a whole `width` x `height` image
+    pub fn buffer_size(&self, width: usize, height: usize) -> usize {
+        width * height * self.channels()
+    }
+
+    // Whether the pixels are taken before the YCbCr to RGB conversion
+    pub(crate) fn is_ycbcr(&self) -> bool {
+        matches!(self, PixelFormat::Gray8 | PixelFormat::YCbCrPlanar)
+    }
+}
+
 pub struct Image {
     width: usize,
     height: usize,
-    image_rgb: [Vec<Vec<u8>>; 3],
+    format: PixelFormat,
+    stride: usize, // bytes from a row to the next one
+    data: Vec<u8>, // rows from top to bottom, plane after plane when planar
 }
 
 impl Image {
     pub fn new(width: usize, height: usize) -> Self {
-        let image_rgb = vec![vec![0; width]; height];
+        Self::with_format(width, height, PixelFormat::Rgb8)
+    }
 
+    pub fn new_grayscale(width: usize, height: usize) -> Self {
+        Self::with_format(width, height, PixelFormat::Gray8)
+    }
+
+    pub fn with_format(width: usize, height: usize, format: PixelFormat) -> Self {
         Self {
             width,
             height,
-            image_rgb: [image_rgb.clone(), image_rgb.clone(), image_rgb.clone()],
+            format,
+            stride: format.stride(width),
+            data: vec![0; format.buffer_size(width, height)],
+        }
+    }
+
+    // Wraps pixels laid out in `format`, returning `None` when `data` does not
+    // hold exactly a `width` x `height` image
+    pub fn from_vec(
+        width: usize,
+        height: usize,
+        format: PixelFormat,
+        data: Vec<u8>,
+    ) -> Option<Self> {
+        if data.len() != format.buffer_size(width, height) {
+            return None;
+        }
+
+        Some(Self {
+            width,
+            height,
+            format,
+            stride: format.stride(width),
+            data,
+        })
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn format(&self) -> PixelFormat {
+        self.format
+    }
+
+    pub fn stride(&self) -> usize {
+        self.stride
+    }
+
+    pub fn is_grayscale(&self) -> bool {
+        self.format == PixelFormat::Gray8
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn into_vec(self) -> Vec<u8> {
+        self.data
+    }
+
+    // Samples of row `y`, from the Y plane when planar
+    pub fn row(&self, y: usize) -> &[u8] {
+        &self.data[y * self.stride..y * self.stride + self.format.stride(self.width)]
+    }
+
+    // One of the Y, Cb and Cr planes when planar, or every pixel otherwise
+    pub fn plane(&self, index: usize) -> &[u8] {
+        let size = self.stride * self.height;
+        match self.format {
+            PixelFormat::YCbCrPlanar => &self.data[index * size..(index + 1) * size],
+            _ => &self.data,
         }
     }
 
-    pub fn build_from_mcus(&mut self, mcus: &Vec<MCU>) {
-        let apparent_width = if self.width % 8 == 0 {
-            self.width
+    // Samples of a pixel in the order of the format, the unused ones being zero
+    pub fn get_pixel(&self, x: usize, y: usize) -> Option<[u8; 4]> {
+        if x >= self.width || y >= self.height {
+            return None;
+        }
+
+        let mut pixel = [0; 4];
+        if self.format == PixelFormat::YCbCrPlanar {
+            for (plane, val) in pixel.iter_mut().take(3).enumerate() {
+                *val = self.plane(plane)[y * self.stride + x];
+            }
         } else {
-            (self.width / 8 + 1) * 8
+            let channels = self.format.channels();
+            let offset = y * self.stride + x * channels;
+            pixel[..channels].copy_from_slice(&self.data[offset..offset + channels]);
+        }
+
+        Some(pixel)
+    }
+
+    // Red, green and blue samples of a pixel, whatever the format
+    pub fn get_rgb(&self, x: usize, y: usize) -> Option<[u8; 3]> {
+        let [c0, c1, c2, _] = self.get_pixel(x, y)?;
+
+        Some(match self.format {
+            PixelFormat::Rgb8 | PixelFormat::Rgba8 => [c0, c1, c2],
+            PixelFormat::Bgr8 | PixelFormat::Bgra8 => [c2, c1, c0],
+            PixelFormat::Gray8 => [c0, c0, c0],
+            PixelFormat::YCbCrPlanar => ycbcr_to_rgb(c0, c1, c2),
+        })
+    }
+
+    // Luma of a pixel, computed with the JFIF weights for RGB formats
+    pub fn get_luma(&self, x: usize, y: usize) -> Option<u8> {
+        if self.format.is_ycbcr() {
+            return self.get_pixel(x, y).map(|pixel| pixel[0]);
+        }
+
+        let [r, g, b] = self.get_rgb(x, y)?.map(|val| val as f64);
+        Some(
+            (0.299 * r + 0.587 * g + 0.114 * b)
+                .round()
+                .clamp(0.0, 255.0) as u8,
+        )
+    }
+
+    // Copies the `width` x `height` pixels at (`x`, `y`), clipped to the image
+    pub fn crop(&self, x: usize, y: usize, width: usize, height: usize) -> Self {
+        let right = x.saturating_add(width).min(self.width);
+        let bottom = y.saturating_add(height).min(self.height);
+        let (x, y) = (x.min(right), y.min(bottom));
+
+        let mut img = Self::with_format(right - x, bottom - y, self.format);
+        let plane_count = if self.format == PixelFormat::YCbCrPlanar {
+            3
+        } else {
+            1
         };
+        let bytes_per_pixel = self.format.stride(1);
+        for plane in 0..plane_count {
+            let src = self.plane(plane);
+            let plane_offset = plane * img.stride * img.height;
+            for row in y..bottom {
+                let start = row * self.stride + x * bytes_per_pixel;
+                let offset = plane_offset + (row - y) * img.stride;
+                img.data[offset..offset + img.stride]
+                    .copy_from_slice(&src[start..start + img.stride]);
+            }
+        }
+
+        img
+    }
+
+    // Expects MCUs converted to RGB, unless the format keeps the Y, Cb and Cr
+    // components
+    pub fn build_from_mcus(&mut self, mcus: &[MCU]) {
+        let (mcu_width, mcu_height) = match mcus.first() {
+            Some(mcu) => (mcu.width(), mcu.height()),
+            None => return,
+        };
+        let mcus_per_row = self.width.div_ceil(mcu_width);
+
         for (mcu_id, mcu) in mcus.iter().enumerate() {
-            let x_0 = (mcu_id * 8) % apparent_width;
-            let y_0 = (mcu_id * 8) / apparent_width * 8;
-
-            for dy in 0..8 {
-                for dx in 0..8 {
-                    if y_0 + dy < self.height && x_0 + dx < self.width {
-                        self.image_rgb[0][y_0 + dy][x_0 + dx] = mcu.rgb[0][dy][dx];
-                        self.image_rgb[1][y_0 + dy][x_0 + dx] = mcu.rgb[1][dy][dx];
-                        self.image_rgb[2][y_0 + dy][x_0 + dx] = mcu.rgb[2][dy][dx];
-                    }
+            let x_0 = (mcu_id % mcus_per_row) * mcu_width;
+            let y_0 = (mcu_id / mcus_per_row) * mcu_height;
+
+            for dy in 0..mcu_height.min(self.height.saturating_sub(y_0)) {
+                for dx in 0..mcu_width.min(self.width - x_0) {
+                    let samples = match &mcu.rgb[..] {
+                        [c0, c1, c2] => [c0[dy][dx], c1[dy][dx], c2[dy][dx]],
+                        // grayscale MCUs have no chroma
+                        [luma] if self.format == PixelFormat::YCbCrPlanar => {
+                            [luma[dy][dx], 128, 128]
+                        }
+                        planes => [planes[0][dy][dx]; 3],
+                    };
+                    self.set_samples(x_0 + dx, y_0 + dy, samples);
                 }
             }
         }
     }
 
-    pub fn dump_to_ppm(&self, path: &str) -> std::io::Result<()> {
-        let file = File::create(path)?;
-        let mut buffer = BufWriter::new(file);
-
-        writeln!(buffer, "P3")?;
-        writeln!(buffer, "{} {}", self.width, self.height)?;
-        writeln!(buffer, "255")?;
-
-        for y in 0..self.height {
-            for x in 0..self.width {
-                writeln!(
-                    buffer,
-                    "{} {} {}",
-                    self.image_rgb[0][y][x], self.image_rgb[1][y][x], self.image_rgb[2][y][x]
-                )?;
+    // Stores the RGB (or YCbCr) samples of a pixel in the layout of the format
+    fn set_samples(&mut self, x: usize, y: usize, [c0, c1, c2]: [u8; 3]) {
+        let offset = y * self.stride + x * self.format.stride(1);
+        match self.format {
+            PixelFormat::Rgb8 => self.data[offset..offset + 3].copy_from_slice(&[c0, c1, c2]),
+            PixelFormat::Rgba8 => self.data[offset..offset + 4].copy_from_slice(&[c0, c1, c2, 255]),
+            PixelFormat::Bgr8 => self.data[offset..offset + 3].copy_from_slice(&[c2, c1, c0]),
+            PixelFormat::Bgra8 => self.data[offset..offset + 4].copy_from_slice(&[c2, c1, c0, 255]),
+            PixelFormat::Gray8 => self.data[offset] = c0,
+            PixelFormat::YCbCrPlanar => {
+                let plane_size = self.stride * self.height;
+                for (plane, val) in [c0, c1, c2].iter().enumerate() {
+                    self.data[plane * plane_size + offset] = *val;
+                }
             }
         }
+    }
 
-        buffer.flush()?;
+    // Writes the image as a binary PPM, see `pnm::write_ppm`
+    pub fn dump_to_ppm(&self, path: &str) -> std::io::Result<()> {
+        let mut buffer = BufWriter::new(File::create(path)?);
+        pnm::write_ppm(self, &mut buffer)?;
+        buffer.flush()
+    }
 
-        Ok(())
+    // Writes the luma of the image as a binary PGM, see `pnm::write_pgm`
+    pub fn dump_to_pgm(&self, path: &str) -> std::io::Result<()> {
+        let mut buffer = BufWriter::new(File::create(path)?);
+        pnm::write_pgm(self, &mut buffer)?;
+        buffer.flush()
     }
 }
@@ -1,5 +1,17 @@
+pub mod bit_reader;
+pub mod bit_writer;
+pub mod bmp;
 pub mod codes_markers;
+pub mod coefficients;
+pub mod component;
 pub mod decoder;
+pub mod encoder;
+pub mod error;
 pub mod huffman_tree;
+pub mod idct;
 pub mod image;
 pub mod mcu;
+pub mod png;
+pub mod pnm;
+pub mod segment;
+pub mod transform;
@@ -0,0 +1,246 @@
+use rpeg::codes_markers::*;
+use rpeg::decoder::Decoder;
+use rpeg::error::{DecodeError, DecodeErrorKind};
+use rpeg::segment::Segment;
+use rpeg::{bmp, png, pnm};
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Write};
+use std::process;
+
+const USAGE: &str = "usage:
+    rpeg decode <input> -o <output> [--format ppm|pgm|pam|png|bmp]
+    rpeg info <input>
+    rpeg dump-segments <input>
+
+A `-` input reads the image from stdin and a `-` output writes it to stdout,
+as a PPM unless --format says otherwise. The format of other outputs follows
+their extension.
+
+exit codes:
+    0  success
+    1  invalid arguments
+    2  i/o error
+    3  the input is not a JPEG image
+    4  the image is malformed
+    5  the image uses an unsupported feature or is too large";
+
+// Everything that stops a command, with the exit code reporting it
+enum Failure {
+    Usage(String),
+    Io(io::Error),
+    Decode(DecodeError),
+}
+
+impl From<io::Error> for Failure {
+    fn from(err: io::Error) -> Self {
+        Failure::Io(err)
+    }
+}
+
+impl From<DecodeError> for Failure {
+    fn from(err: DecodeError) -> Self {
+        Failure::Decode(err)
+    }
+}
+
+impl Failure {
+    fn exit_code(&self) -> i32 {
+        match self {
+            Failure::Usage(_) => 1,
+            Failure::Io(_) => 2,
+            Failure::Decode(err) => match err.kind() {
+                DecodeErrorKind::Io(_) => 2,
+                DecodeErrorKind::NotJpeg => 3,
+                DecodeErrorKind::Unsupported(_) | DecodeErrorKind::ImageTooLarge { .. } => 5,
+                _ => 4,
+            },
+        }
+    }
+}
+
+fn main() {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    if let Err(failure) = run(&args) {
+        match &failure {
+            Failure::Usage(reason) => eprintln!("rpeg: {}\n\n{}", reason, USAGE),
+            Failure::Io(err) => eprintln!("rpeg: {}", err),
+            Failure::Decode(err) => eprintln!("rpeg: {}", err),
+        }
+        process::exit(failure.exit_code());
+    }
+}
+
+fn run(args: &[String]) -> Result<(), Failure> {
+    let (command, rest) = args
+        .split_first()
+        .ok_or_else(|| Failure::Usage("missing command".to_string()))?;
+
+    match command.as_str() {
+        "decode" => decode(rest),
+        "info" => info(single_input(rest)?),
+        "dump-segments" => dump_segments(single_input(rest)?),
+        "-h" | "--help" | "help" => {
+            println!("{}", USAGE);
+            Ok(())
+        }
+        _ => Err(Failure::Usage(format!("unknown command '{}'", command))),
+    }
+}
+
+fn single_input(args: &[String]) -> Result<&str, Failure> {
+    match args {
+        [input] => Ok(input),
+        _ => Err(Failure::Usage("expected a single input".to_string())),
+    }
+}
+
+fn open(input: &str) -> Result<Decoder, Failure> {
+    if input == "-" {
+        Ok(Decoder::from_reader(io::stdin().lock())?)
+    } else {
+        Ok(Decoder::new(input)?)
+    }
+}
+
+fn decode(args: &[String]) -> Result<(), Failure> {
+    let (mut input, mut output, mut format) = (None, None, None);
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" | "--output" => output = args.next(),
+            "--format" => format = args.next().map(|format| format.as_str()),
+            _ if input.is_none() => input = Some(arg),
+            _ => return Err(Failure::Usage(format!("unexpected argument '{}'", arg))),
+        }
+    }
+    let input = input.ok_or_else(|| Failure::Usage("missing input".to_string()))?;
+    let output = output.ok_or_else(|| Failure::Usage("missing output".to_string()))?;
+
+    let format = match format {
+        Some(format) => format,
+        None if output == "-" => "ppm",
+        None => output
+            .rsplit_once('.')
+            .map_or("", |(_, extension)| extension),
+    };
+    if !["ppm", "pgm", "pam", "png", "bmp"].contains(&format) {
+        return Err(Failure::Usage(format!(
+            "unknown output format '{}'",
+            format
+        )));
+    }
+
+    let img = open(input)?.decode()?;
+
+    let mut writer: BufWriter<Box<dyn Write>> = BufWriter::new(if output == "-" {
+        Box::new(io::stdout().lock())
+    } else {
+        Box::new(File::create(output)?)
+    });
+    match format {
+        "ppm" => pnm::write_ppm(&img, &mut writer)?,
+        "pgm" => pnm::write_pgm(&img, &mut writer)?,
+        "pam" => pnm::write_pam(&img, &mut writer)?,
+        "png" => png::write_png(&img, &mut writer, png::Compression::Fast)?,
+        _ => bmp::write_bmp(&img, &mut writer)?,
+    }
+    writer.flush()?;
+
+    Ok(())
+}
+
+fn info(input: &str) -> Result<(), Failure> {
+    let mut decoder = open(input)?;
+    decoder.inspect()?;
+    let segments = decoder.segments();
+    if decoder.components().is_empty() {
+        return Err(DecodeError::new(DecodeErrorKind::MissingFrame, 0).into());
+    }
+
+    let mut out = io::stdout().lock();
+    writeln!(out, "size: {}x{}", decoder.width(), decoder.height())?;
+    for segment in segments.iter() {
+        match segment {
+            Segment::Sof {
+                marker, precision, ..
+            } => {
+                let kind = match *marker {
+                    JFIF_SOF0 => "baseline",
+                    JFIF_SOF1 => "extended sequential",
+                    _ => "progressive",
+                };
+                writeln!(out, "frame: {} ({}-bit samples)", kind, precision)?;
+            }
+            Segment::Dri { interval, .. } => writeln!(out, "restart interval: {} MCUs", interval)?,
+            _ => (),
+        }
+    }
+
+    writeln!(out, "components:")?;
+    for component in decoder.components() {
+        writeln!(
+            out,
+            "\t{}: sampling {}x{}, quantization table {}",
+            component.id,
+            component.horizontal_sampling,
+            component.vertical_sampling,
+            component.quantization_table
+        )?;
+    }
+
+    writeln!(out, "tables:")?;
+    for segment in segments.iter() {
+        match segment {
+            Segment::Dqt { tables, .. } => {
+                for (precision, destination, _) in tables.iter() {
+                    let bits = if *precision == 0 { 8 } else { 16 };
+                    writeln!(out, "\tquantization {} ({}-bit)", destination, bits)?;
+                }
+            }
+            Segment::Dht { tables, .. } => {
+                for (class, id, symbols) in tables.iter() {
+                    let class = if *class == HUFFMAN_DC { "dc" } else { "ac" };
+                    writeln!(out, "\thuffman {} {} ({} symbols)", class, id, symbols)?;
+                }
+            }
+            _ => (),
+        }
+    }
+
+    let markers = segments.iter().map(marker_name).collect::<Vec<_>>();
+    writeln!(out, "markers: SOI {}", markers.join(" "))?;
+
+    Ok(())
+}
+
+fn marker_name(segment: &Segment) -> String {
+    match segment {
+        Segment::App0 { .. } => "APP0".to_string(),
+        Segment::Dqt { .. } => "DQT".to_string(),
+        Segment::Dht { .. } => "DHT".to_string(),
+        Segment::Sof { marker, .. } => format!("SOF{}", marker - JFIF_SOF0),
+        Segment::Sos { .. } => "SOS".to_string(),
+        Segment::Dri { .. } => "DRI".to_string(),
+        Segment::Comment { .. } => "COM".to_string(),
+        Segment::Unknown { marker, .. } => match *marker {
+            JFIF_RST0..=JFIF_RST7 => format!("RST{}", marker - JFIF_RST0),
+            0xE1..=0xEF => format!("APP{}", marker - JFIF_APP0),
+            _ => format!("FF{:02X}", marker),
+        },
+        Segment::EndOfImage => "EOI".to_string(),
+    }
+}
+
+fn dump_segments(input: &str) -> Result<(), Failure> {
+    let mut decoder = open(input)?;
+    let res = decoder.inspect().map(|_| ());
+
+    // the segments read before an error are still worth showing
+    let mut out = io::stdout().lock();
+    for segment in decoder.segments() {
+        writeln!(out, "{}", segment)?;
+    }
+
+    Ok(res?)
+}
@@ -1,14 +1,17 @@
-use std::f64::consts::PI;
-use std::f64::consts::SQRT_2;
+use crate::error::DecodeErrorKind;
+use crate::idct::{idct, idct_reduced, IdctMethod, Scale};
 
 pub struct MCU {
     order: usize,
-    pub zz_dct_coeff: [[i32; 64]; 3],
-    pub idct_coeff: [[[f64; 8]; 8]; 3],
-    pub rgb: [[[u8; 8]; 8]; 3],
+    sampling: Vec<(usize, usize)>, // (horizontal, vertical) sampling factor of every component
+    pub zz_dct_coeff: Vec<Vec<[i32; 64]>>, // blocks of every component, left to right, top to bottom
+    pub idct_coeff: Vec<Vec<[[f64; 8]; 8]>>, // only the top-left block_size x block_size samples are used
+    pub rgb: Vec<Vec<Vec<u8>>>, // one (block_size * max vertical) x (block_size * max horizontal) plane per component
+    block_size: usize,          // side of every block once decoded, 8 unless scaled down
 }
 
-const TABLE: [[usize; 8]; 8] = [
+// Zigzag index of every coefficient of a block, in natural order
+pub const ZIGZAG: [[usize; 8]; 8] = [
     [0, 1, 5, 6, 14, 15, 27, 28],
     [2, 4, 7, 13, 16, 26, 29, 42],
     [3, 8, 12, 17, 25, 30, 41, 43],
@@ -19,160 +22,187 @@ const TABLE: [[usize; 8]; 8] = [
     [35, 36, 48, 49, 57, 58, 62, 63],
 ];
 
-fn lin_coord_to_two_coord(i: usize) -> (usize, usize) {
-    if i > 63 {
-        panic!("[E] - index out of bounds ({} is not in [0, 63])", i);
-    }
-    for y in 0..8 {
-        for x in 0..8 {
-            if TABLE[y][x] == i {
-                return (x, y);
-            }
-        }
-    }
-    (0, 0)
+pub(crate) fn ycbcr_to_rgb(y: u8, cb: u8, cr: u8) -> [u8; 3] {
+    let (y, cb, cr) = (y as f64, cb as f64 - 128.0, cr as f64 - 128.0);
+
+    let r = y + 1.402 * cr;
+    let g = y - 0.344136 * cb - 0.714136 * cr;
+    let b = y + 1.772 * cb;
+
+    [r, g, b].map(|val| val.clamp(0.0, 255.0).round() as u8)
 }
 
 impl MCU {
-    pub fn new(mcu_order: usize, run_length_encoding: Vec<Vec<i32>>, dc_sums: [i32; 3]) -> Self {
-        if run_length_encoding.len() != 3 {
-            panic!(
-                "[E] - you need 3 components to build an MCU ({} != 3)",
-                run_length_encoding.len()
-            );
+    pub fn new(
+        mcu_order: usize,
+        zz_dct_coeff: Vec<Vec<[i32; 64]>>,
+        sampling: Vec<(usize, usize)>,
+    ) -> Result<Self, DecodeErrorKind> {
+        if !(zz_dct_coeff.len() == 1 || zz_dct_coeff.len() == 3)
+            || zz_dct_coeff.len() != sampling.len()
+        {
+            return Err(DecodeErrorKind::Unsupported("MCUs need 1 or 3 components"));
         }
-
-        let mut zz_dct_coeff = [[0; 64]; 3];
-
-        for (comp_id, comp) in run_length_encoding.iter().enumerate() {
-            let mut index = 0;
-            for i in (0..comp.len()).step_by(2) {
-                let zeros = comp[i] as usize;
-                let val = comp[i + 1];
-
-                if zeros == 0 && val == 0 {
-                    break;
-                }
-
-                //TODO: do not iterate over the already existing zeros, just change the index var
-                for _ in 0..zeros {
-                    zz_dct_coeff[comp_id][index] = 0;
-                    index += 1;
-                }
-
-                zz_dct_coeff[comp_id][index] = val;
-                index += 1;
+        for (blocks, (h, v)) in zz_dct_coeff.iter().zip(sampling.iter()) {
+            if blocks.len() != h * v {
+                return Err(DecodeErrorKind::InvalidSegment(
+                    "block count does not match the sampling factors",
+                ));
             }
-            zz_dct_coeff[comp_id][0] += dc_sums[comp_id];
         }
 
-        Self {
+        let h_max = sampling.iter().map(|(h, _)| *h).max().unwrap_or(1);
+        let v_max = sampling.iter().map(|(_, v)| *v).max().unwrap_or(1);
+        let plane = vec![vec![0; 8 * h_max]; 8 * v_max];
+
+        Ok(Self {
             order: mcu_order,
+            idct_coeff: zz_dct_coeff
+                .iter()
+                .map(|blocks| vec![[[0.0; 8]; 8]; blocks.len()])
+                .collect(),
             zz_dct_coeff,
-            idct_coeff: [[[0.0; 8]; 8]; 3],
-            rgb: [[[0; 8]; 8]; 3],
-        }
+            rgb: vec![plane; sampling.len()],
+            sampling,
+            block_size: 8,
+        })
+    }
+
+    pub fn width(&self) -> usize {
+        self.rgb[0][0].len()
+    }
+
+    pub fn height(&self) -> usize {
+        self.rgb[0].len()
     }
 
+    // Expects one quantization table (in zigzag order) per component
     pub fn build_rgb_block(
         &mut self,
-        quantization_luma: &[[u8; 8]; 8],
-        quantization_chroma: &[[u8; 8]; 8],
+        quantization_tables: &[[u16; 64]],
+        idct_method: IdctMethod,
+        scale: Scale,
     ) {
-        self.compute_idct(quantization_luma, quantization_chroma);
-        self.level_shift();
-        self.convert_ycbcr_to_rgb();
+        self.build_ycbcr_block(quantization_tables, idct_method, scale);
+        if self.rgb.len() == 3 {
+            self.convert_ycbcr_to_rgb();
+        }
     }
 
-    fn compute_idct(
+    // Same as `build_rgb_block`, leaving the samples in the YCbCr color space
+    pub fn build_ycbcr_block(
         &mut self,
-        quantization_luma: &[[u8; 8]; 8],
-        quantization_chroma: &[[u8; 8]; 8],
+        quantization_tables: &[[u16; 64]],
+        idct_method: IdctMethod,
+        scale: Scale,
     ) {
-        let mut temp = [[[0; 8]; 8]; 3];
-        for comp_id in 0..3 {
-            for i in 0..64 {
-                let (coord_x, coord_y) = lin_coord_to_two_coord(i);
-                if comp_id == 0 {
-                    temp[comp_id][coord_y][coord_x] =
-                        self.zz_dct_coeff[comp_id][i] * quantization_luma[i / 8][i % 8] as i32;
-                } else {
-                    temp[comp_id][coord_y][coord_x] =
-                        self.zz_dct_coeff[comp_id][i] * quantization_chroma[i / 8][i % 8] as i32;
-                }
-            }
+        if scale.block_size() != self.block_size {
+            self.block_size = scale.block_size();
+            let (h_max, v_max) = self.max_sampling();
+            let plane = vec![vec![0; self.block_size * h_max]; self.block_size * v_max];
+            self.rgb = vec![plane; self.rgb.len()];
         }
 
-        for comp_id in 0..3 {
-            for y in 0..8 {
-                for x in 0..8 {
-                    let mut sum: f64 = 0.0;
-                    for u in 0..8 {
-                        for v in 0..8 {
-                            let cu = if u == 0 { 1.0 / SQRT_2 } else { 1.0 };
-                            let cv = if v == 0 { 1.0 / SQRT_2 } else { 1.0 };
-                            sum += temp[comp_id][u][v] as f64
-                                * cu
-                                * cv
-                                * f64::cos(((2 * x + 1) * u) as f64 * PI / 16.0)
-                                * f64::cos(((2 * y + 1) * v) as f64 * PI / 16.0);
-                        }
-                    }
-                    self.idct_coeff[comp_id][x][y] = sum / 4.0;
+        self.compute_idct(quantization_tables, idct_method);
+        self.level_shift();
+    }
+
+    fn compute_idct(&mut self, quantization_tables: &[[u16; 64]], idct_method: IdctMethod) {
+        for (comp_id, blocks) in self.zz_dct_coeff.iter().enumerate() {
+            let quantization = &quantization_tables[comp_id];
+            for (block_id, zz_block) in blocks.iter().enumerate() {
+                if self.block_size == 1 {
+                    // a single sample only depends on the DC coefficient
+                    let dc = dequantize(zz_block[0], quantization[0]);
+                    self.idct_coeff[comp_id][block_id][0][0] = dc as f64 / 8.0;
+                    continue;
+                }
+
+                let mut dequantized = [0; 64];
+                for (i, zz_index) in ZIGZAG.iter().flatten().enumerate() {
+                    dequantized[i] = dequantize(zz_block[*zz_index], quantization[*zz_index]);
                 }
+
+                self.idct_coeff[comp_id][block_id] = if self.block_size == 8 {
+                    idct(idct_method, &dequantized)
+                } else {
+                    idct_reduced(&dequantized, self.block_size)
+                };
             }
         }
     }
 
+    // Level shifts every block and places it in the MCU, replicating the
+    // samples of subsampled components to cover the whole MCU
     fn level_shift(&mut self) {
-        for comp_id in 0..3 {
-            for y in 0..8 {
-                for x in 0..8 {
-                    self.rgb[comp_id][y][x] =
-                        (self.idct_coeff[comp_id][y][x] + 128.0).clamp(0.0, 255.0) as u8;
+        let (h_max, v_max) = self.max_sampling();
+        let size = self.block_size;
+
+        for (comp_id, blocks) in self.idct_coeff.iter().enumerate() {
+            let (h, v) = self.sampling[comp_id];
+            let (scale_x, scale_y) = (h_max / h, v_max / v);
+
+            for (block_id, block) in blocks.iter().enumerate() {
+                let x_0 = (block_id % h) * size * scale_x;
+                let y_0 = (block_id / h) * size * scale_y;
+
+                for (y, row) in block.iter().enumerate().take(size) {
+                    for (x, val) in row.iter().enumerate().take(size) {
+                        let sample = (val + 128.0).clamp(0.0, 255.0) as u8;
+                        for dy in 0..scale_y {
+                            for dx in 0..scale_x {
+                                self.rgb[comp_id][y_0 + y * scale_y + dy][x_0 + x * scale_x + dx] =
+                                    sample;
+                            }
+                        }
+                    }
                 }
             }
         }
     }
 
+    fn max_sampling(&self) -> (usize, usize) {
+        (
+            self.sampling.iter().map(|(h, _)| *h).max().unwrap_or(1),
+            self.sampling.iter().map(|(_, v)| *v).max().unwrap_or(1),
+        )
+    }
+
     fn convert_ycbcr_to_rgb(&mut self) {
-        for i in 0..8 {
-            for j in 0..8 {
-                let y = self.rgb[0][i][j] as f64;
-                let cb = self.rgb[1][i][j] as f64;
-                let cr = self.rgb[2][i][j] as f64;
-
-                let r = y + 1.402 * (cr - 128.0);
-                let g = y - 0.344136 * (cb - 128.0) - 0.714136 * (cr - 128.0);
-                let b = y + 1.772 * (cb - 128.0);
-
-                self.rgb[0][i][j] = r.clamp(0.0, 255.0).round() as u8;
-                self.rgb[1][i][j] = g.clamp(0.0, 255.0).round() as u8;
-                self.rgb[2][i][j] = b.clamp(0.0, 255.0).round() as u8;
+        for i in 0..self.height() {
+            for j in 0..self.width() {
+                let [r, g, b] =
+                    ycbcr_to_rgb(self.rgb[0][i][j], self.rgb[1][i][j], self.rgb[2][i][j]);
+
+                self.rgb[0][i][j] = r;
+                self.rgb[1][i][j] = g;
+                self.rgb[2][i][j] = b;
             }
         }
     }
 
     pub fn print(&self) {
         println!("MCU {}", self.order);
-        for i in 0..3 {
-            for x in 0..8 {
-                print!("\t");
-                for y in 0..8 {
-                    print!("{} ", self.idct_coeff[i][x][y]);
+        for blocks in self.idct_coeff.iter() {
+            for block in blocks.iter() {
+                for row in block.iter() {
+                    print!("\t");
+                    for val in row.iter() {
+                        print!("{} ", val);
+                    }
+                    println!();
                 }
                 println!();
             }
-            println!();
         }
     }
     pub fn print_rgb(&self) {
         println!("MCU {}", self.order);
-        for i in 0..3 {
-            for x in 0..8 {
+        for plane in self.rgb.iter() {
+            for row in plane.iter() {
                 print!("\t");
-                for y in 0..8 {
-                    print!("{} ", self.rgb[i][x][y]);
+                for val in row.iter() {
+                    print!("{} ", val);
                 }
                 println!();
             }
@@ -180,3 +210,32 @@ impl MCU {
         }
     }
 }
+
+// Multiplies a coefficient by its quantization step. 16-bit tables can push the
+// product past i32, so it is clamped to the 16-bit range, which already covers
+// every coefficient of 8-bit samples.
+fn dequantize(coefficient: i32, quantization: u16) -> i32 {
+    (coefficient as i64 * quantization as i64).clamp(i16::MIN as i64, i16::MAX as i64) as i32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dequantizes_16_bit_tables_without_overflow() {
+        // a DC of 34000 is reachable with legal differences, and times 65535 it
+        // no longer fits an i32
+        let mut block = [0; 64];
+        block[0] = 34000;
+        let tables = [[u16::MAX; 64]];
+
+        for method in [IdctMethod::SeparableFloat, IdctMethod::IntegerSlow].iter() {
+            for scale in [Scale::Full, Scale::Eighth].iter() {
+                let mut mcu = MCU::new(0, vec![vec![block]], vec![(1, 1)]).unwrap();
+                mcu.build_ycbcr_block(&tables, *method, *scale);
+                assert_eq!(mcu.rgb[0][0][0], 255);
+            }
+        }
+    }
+}
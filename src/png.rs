@@ -0,0 +1,602 @@
+use crate::image::{Image, PixelFormat};
+use std::io;
+use std::io::Write;
+use std::sync::OnceLock;
+
+const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+
+const WINDOW_SIZE: usize = 1 << 15;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+const HASH_BITS: u32 = 15;
+const MAX_CHAIN: usize = 64; // earlier positions tried before settling on a match
+
+// First length of every length code from 257, and its extra bits
+const LENGTH_BASES: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA_BITS: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+// First distance of every distance code, and its extra bits
+const DISTANCE_BASES: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA_BITS: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+
+// How the image data of a PNG file is deflated
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Compression {
+    // stored blocks, as large as the filtered image
+    Stored,
+    // greedy LZ77 matches with the fixed Huffman codes, after filtering every row
+    #[default]
+    Fast,
+}
+
+// Writes the image as an 8-bit grayscale, RGB or RGBA PNG depending on its format
+pub fn write_png<W: Write>(
+    img: &Image,
+    writer: &mut W,
+    compression: Compression,
+) -> io::Result<()> {
+    if img.width() == 0
+        || img.height() == 0
+        || img.width() > 0x7FFF_FFFF
+        || img.height() > 0x7FFF_FFFF
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "PNG images must have between 1 and 2^31 - 1 rows and columns",
+        ));
+    }
+
+    let (channels, color_type) = match img.format() {
+        PixelFormat::Gray8 => (1, 0),
+        PixelFormat::Rgba8 | PixelFormat::Bgra8 => (4, 6),
+        _ => (3, 2),
+    };
+
+    writer.write_all(&SIGNATURE)?;
+
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&(img.width() as u32).to_be_bytes());
+    header.extend_from_slice(&(img.height() as u32).to_be_bytes());
+    // 8 bits per sample, deflate, adaptive filtering and no interlacing
+    header.extend_from_slice(&[8, color_type, 0, 0, 0]);
+    write_chunk(writer, b"IHDR", &header)?;
+
+    let rows = filtered_rows(img, channels, compression == Compression::Fast);
+    write_chunk(writer, b"IDAT", &zlib(&rows, compression))?;
+    write_chunk(writer, b"IEND", &[])
+}
+
+fn write_chunk<W: Write>(writer: &mut W, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    writer.write_all(&(data.len() as u32).to_be_bytes())?;
+    writer.write_all(kind)?;
+    writer.write_all(data)?;
+
+    let crc = crc32_update(crc32_update(0xFFFF_FFFF, kind), data) ^ 0xFFFF_FFFF;
+    writer.write_all(&crc.to_be_bytes())
+}
+
+fn crc32_table() -> &'static [u32; 256] {
+    static TABLE: OnceLock<[u32; 256]> = OnceLock::new();
+    TABLE.get_or_init(|| {
+        let mut table = [0; 256];
+        for (n, entry) in table.iter_mut().enumerate() {
+            let mut c = n as u32;
+            for _ in 0..8 {
+                c = if c & 1 == 1 {
+                    0xEDB8_8320 ^ (c >> 1)
+                } else {
+                    c >> 1
+                };
+            }
+            *entry = c;
+        }
+        table
+    })
+}
+
+fn crc32_update(mut crc: u32, bytes: &[u8]) -> u32 {
+    let table = crc32_table();
+    for byte in bytes.iter() {
+        crc = table[((crc ^ *byte as u32) & 0xFF) as usize] ^ (crc >> 8);
+    }
+
+    crc
+}
+
+fn adler32(bytes: &[u8]) -> u32 {
+    // 5552 bytes can be summed before the sums may overflow
+    let (mut a, mut b) = (1u32, 0u32);
+    for chunk in bytes.chunks(5552) {
+        for byte in chunk.iter() {
+            a += *byte as u32;
+            b += a;
+        }
+        a %= 65521;
+        b %= 65521;
+    }
+
+    (b << 16) | a
+}
+
+// Rows of the image, each preceded by its filter type. When `filter` is set,
+// every row uses the filter with the smallest sum of absolute differences.
+fn filtered_rows(img: &Image, channels: usize, filter: bool) -> Vec<u8> {
+    let row_size = img.width() * channels;
+    let mut rows = Vec::with_capacity((row_size + 1) * img.height());
+    let mut previous = vec![0; row_size];
+    let mut current = Vec::with_capacity(row_size);
+    let mut candidate = vec![0; row_size];
+    let mut best = vec![0; row_size];
+
+    for y in 0..img.height() {
+        current.clear();
+        for x in 0..img.width() {
+            match channels {
+                1 => current.push(img.get_luma(x, y).unwrap_or_default()),
+                _ => current.extend_from_slice(&img.get_rgb(x, y).unwrap_or_default()),
+            }
+            if channels == 4 {
+                current.push(img.get_pixel(x, y).unwrap_or_default()[3]);
+            }
+        }
+
+        if !filter {
+            rows.push(0);
+            rows.extend_from_slice(&current);
+            continue;
+        }
+
+        let mut best_filter = 0;
+        let mut best_cost = u64::MAX;
+        for filter_type in 0..5 {
+            for i in 0..row_size {
+                let left = if i >= channels {
+                    current[i - channels]
+                } else {
+                    0
+                };
+                let up = previous[i];
+                let up_left = if i >= channels {
+                    previous[i - channels]
+                } else {
+                    0
+                };
+                let predicted = match filter_type {
+                    0 => 0,
+                    1 => left,
+                    2 => up,
+                    3 => ((left as u16 + up as u16) / 2) as u8,
+                    _ => paeth(left, up, up_left),
+                };
+                candidate[i] = current[i].wrapping_sub(predicted);
+            }
+
+            // residuals are read as signed bytes, small ones compress better
+            let cost = candidate
+                .iter()
+                .map(|val| (*val as i8).unsigned_abs() as u64)
+                .sum();
+            if cost < best_cost {
+                best_cost = cost;
+                best_filter = filter_type;
+                std::mem::swap(&mut best, &mut candidate);
+            }
+        }
+
+        rows.push(best_filter);
+        rows.extend_from_slice(&best);
+        std::mem::swap(&mut previous, &mut current);
+    }
+
+    rows
+}
+
+fn paeth(left: u8, up: u8, up_left: u8) -> u8 {
+    let estimate = left as i16 + up as i16 - up_left as i16;
+    let (to_left, to_up, to_up_left) = (
+        (estimate - left as i16).abs(),
+        (estimate - up as i16).abs(),
+        (estimate - up_left as i16).abs(),
+    );
+
+    if to_left <= to_up && to_left <= to_up_left {
+        left
+    } else if to_up <= to_up_left {
+        up
+    } else {
+        up_left
+    }
+}
+
+// Wraps the deflated `data` in a zlib stream
+fn zlib(data: &[u8], compression: Compression) -> Vec<u8> {
+    // 32K window, no preset dictionary, check bits making the header a multiple of 31
+    let mut writer = LsbBitWriter {
+        bytes: vec![0x78, 0x01],
+        bit_buffer: 0,
+        bit_count: 0,
+    };
+    match compression {
+        Compression::Stored => deflate_stored(&mut writer, data),
+        Compression::Fast => deflate_fixed(&mut writer, data),
+    }
+
+    let mut bytes = writer.into_bytes();
+    bytes.extend_from_slice(&adler32(data).to_be_bytes());
+
+    bytes
+}
+
+fn deflate_stored(writer: &mut LsbBitWriter, data: &[u8]) {
+    let mut blocks = data.chunks(0xFFFF).peekable();
+    if blocks.peek().is_none() {
+        writer.write_bits(1, 3);
+        writer.align();
+        writer.bytes.extend_from_slice(&[0x00, 0x00, 0xFF, 0xFF]);
+        return;
+    }
+
+    while let Some(block) = blocks.next() {
+        // BFINAL, then BTYPE 00
+        writer.write_bits(blocks.peek().is_none() as u32, 3);
+        writer.align();
+        let length = block.len() as u16;
+        writer.bytes.extend_from_slice(&length.to_le_bytes());
+        writer.bytes.extend_from_slice(&(!length).to_le_bytes());
+        writer.bytes.extend_from_slice(block);
+    }
+}
+
+// A single block with the fixed Huffman codes (BTYPE 01), fed by greedy matches
+// found through hash chains of the last three bytes
+fn deflate_fixed(writer: &mut LsbBitWriter, data: &[u8]) {
+    writer.write_bits(1, 1);
+    writer.write_bits(1, 2);
+
+    let mut head = vec![usize::MAX; 1 << HASH_BITS];
+    let mut previous = vec![usize::MAX; WINDOW_SIZE];
+
+    let mut pos = 0;
+    while pos < data.len() {
+        let (mut best_length, mut best_distance) = (0, 0);
+        if pos + MIN_MATCH <= data.len() {
+            let max_length = MAX_MATCH.min(data.len() - pos);
+            let mut candidate = head[hash(data, pos)];
+            let mut chain = 0;
+            while candidate != usize::MAX && pos - candidate <= WINDOW_SIZE && chain < MAX_CHAIN {
+                let length = data[candidate..]
+                    .iter()
+                    .zip(data[pos..pos + max_length].iter())
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                if length > best_length {
+                    best_length = length;
+                    best_distance = pos - candidate;
+                    if length == max_length {
+                        break;
+                    }
+                }
+                let next = previous[candidate % WINDOW_SIZE];
+                // older entries of the ring buffer may have been overwritten
+                if next == usize::MAX || next >= candidate {
+                    break;
+                }
+                candidate = next;
+                chain += 1;
+            }
+        }
+
+        if best_length >= MIN_MATCH {
+            write_length(writer, best_length);
+            write_distance(writer, best_distance);
+            for p in pos..pos + best_length {
+                insert(data, p, &mut head, &mut previous);
+            }
+            pos += best_length;
+        } else {
+            write_literal(writer, data[pos] as u16);
+            insert(data, pos, &mut head, &mut previous);
+            pos += 1;
+        }
+    }
+
+    write_literal(writer, 256);
+}
+
+fn hash(data: &[u8], pos: usize) -> usize {
+    let key = (data[pos] as u32) << 16 | (data[pos + 1] as u32) << 8 | data[pos + 2] as u32;
+    (key.wrapping_mul(0x9E37_79B1) >> (32 - HASH_BITS)) as usize
+}
+
+// Chains `pos` in front of the earlier positions starting with the same bytes
+fn insert(data: &[u8], pos: usize, head: &mut [usize], previous: &mut [usize]) {
+    if pos + MIN_MATCH <= data.len() {
+        let h = hash(data, pos);
+        previous[pos % WINDOW_SIZE] = head[h];
+        head[h] = pos;
+    }
+}
+
+// Writes a literal, length or end of block symbol with its fixed Huffman code
+fn write_literal(writer: &mut LsbBitWriter, symbol: u16) {
+    let (code, length) = match symbol {
+        0..=143 => (0x30 + symbol, 8),
+        144..=255 => (0x190 + symbol - 144, 9),
+        256..=279 => (symbol - 256, 7),
+        _ => (0xC0 + symbol - 280, 8),
+    };
+    writer.write_huffman(code, length);
+}
+
+fn write_length(writer: &mut LsbBitWriter, length: usize) {
+    let code = LENGTH_BASES
+        .iter()
+        .rposition(|base| *base as usize <= length)
+        .unwrap_or(0);
+    write_literal(writer, 257 + code as u16);
+    writer.write_bits(
+        (length - LENGTH_BASES[code] as usize) as u32,
+        LENGTH_EXTRA_BITS[code],
+    );
+}
+
+fn write_distance(writer: &mut LsbBitWriter, distance: usize) {
+    let code = DISTANCE_BASES
+        .iter()
+        .rposition(|base| *base as usize <= distance)
+        .unwrap_or(0);
+    writer.write_huffman(code as u16, 5);
+    writer.write_bits(
+        (distance - DISTANCE_BASES[code] as usize) as u32,
+        DISTANCE_EXTRA_BITS[code],
+    );
+}
+
+// Packs bits starting from the least significant one of every byte, as deflate
+// does, without any byte stuffing
+struct LsbBitWriter {
+    bytes: Vec<u8>,
+    bit_buffer: u32,
+    bit_count: u8,
+}
+
+impl LsbBitWriter {
+    fn write_bits(&mut self, bits: u32, count: u8) {
+        self.bit_buffer |= bits << self.bit_count;
+        self.bit_count += count;
+        while self.bit_count >= 8 {
+            self.bytes.push(self.bit_buffer as u8);
+            self.bit_buffer >>= 8;
+            self.bit_count -= 8;
+        }
+    }
+
+    // Huffman codes are packed starting from their most significant bit
+    fn write_huffman(&mut self, code: u16, length: u8) {
+        let reversed = code.reverse_bits() >> (16 - length);
+        self.write_bits(reversed as u32, length);
+    }
+
+    fn align(&mut self) {
+        if self.bit_count > 0 {
+            self.write_bits(0, 8 - self.bit_count);
+        }
+    }
+
+    fn into_bytes(mut self) -> Vec<u8> {
+        self.align();
+        self.bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Reads deflate streams one bit at a time, least significant bit first
+    struct LsbBitReader<'a> {
+        bytes: &'a [u8],
+        pos: usize, // in bits
+    }
+
+    impl LsbBitReader<'_> {
+        fn bits(&mut self, count: u8) -> u32 {
+            let mut bits = 0;
+            for i in 0..count {
+                let bit = self.bytes[self.pos / 8] >> (self.pos % 8) & 1;
+                bits |= (bit as u32) << i;
+                self.pos += 1;
+            }
+            bits
+        }
+
+        // Huffman codes start from their most significant bit
+        fn huffman(&mut self, length: u8) -> u16 {
+            (0..length).fold(0, |code, _| code << 1 | self.bits(1) as u16)
+        }
+
+        fn literal(&mut self) -> u16 {
+            let code = self.huffman(7);
+            if code <= 0x17 {
+                return 256 + code;
+            }
+            let code = code << 1 | self.bits(1) as u16;
+            match code {
+                0x30..=0xBF => code - 0x30,
+                0xC0..=0xC7 => 280 + code - 0xC0,
+                _ => 144 + (code << 1 | self.bits(1) as u16) - 0x190,
+            }
+        }
+    }
+
+    // Inflates the stored and fixed Huffman blocks that the encoder writes
+    fn inflate(data: &[u8]) -> Vec<u8> {
+        let mut reader = LsbBitReader {
+            bytes: data,
+            pos: 0,
+        };
+        let mut out: Vec<u8> = Vec::new();
+        loop {
+            let last = reader.bits(1) == 1;
+            match reader.bits(2) {
+                0 => {
+                    reader.pos = reader.pos.div_ceil(8) * 8;
+                    let start = reader.pos / 8;
+                    let length = u16::from_le_bytes([data[start], data[start + 1]]);
+                    let complement = u16::from_le_bytes([data[start + 2], data[start + 3]]);
+                    assert_eq!(length, !complement);
+                    let block = &data[start + 4..start + 4 + length as usize];
+                    out.extend_from_slice(block);
+                    reader.pos += (4 + length as usize) * 8;
+                }
+                1 => loop {
+                    let symbol = reader.literal() as usize;
+                    if symbol < 256 {
+                        out.push(symbol as u8);
+                        continue;
+                    }
+                    if symbol == 256 {
+                        break;
+                    }
+                    let code = symbol - 257;
+                    let length =
+                        LENGTH_BASES[code] as usize + reader.bits(LENGTH_EXTRA_BITS[code]) as usize;
+                    let code = reader.huffman(5) as usize;
+                    let distance = DISTANCE_BASES[code] as usize
+                        + reader.bits(DISTANCE_EXTRA_BITS[code]) as usize;
+                    for _ in 0..length {
+                        out.push(out[out.len() - distance]);
+                    }
+                },
+                _ => panic!("unexpected block type"),
+            }
+            if last {
+                return out;
+            }
+        }
+    }
+
+    fn be_u32(bytes: &[u8]) -> u32 {
+        u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+    }
+
+    // Checks every chunk and returns the size, color type and samples of the image
+    fn read_png(bytes: &[u8]) -> (usize, usize, u8, Vec<u8>) {
+        assert_eq!(bytes[..8], SIGNATURE);
+        let (mut header, mut idat) = (Vec::new(), Vec::new());
+        let mut pos = 8;
+        while pos < bytes.len() {
+            let length = be_u32(&bytes[pos..pos + 4]) as usize;
+            let kind = &bytes[pos + 4..pos + 8];
+            let data = &bytes[pos + 8..pos + 8 + length];
+            let crc = be_u32(&bytes[pos + 8 + length..pos + 12 + length]);
+            assert_eq!(
+                crc,
+                crc32_update(0xFFFF_FFFF, &bytes[pos + 4..pos + 8 + length]) ^ 0xFFFF_FFFF
+            );
+            match kind {
+                b"IHDR" => header = data.to_vec(),
+                b"IDAT" => idat.extend_from_slice(data),
+                _ => assert_eq!(kind, b"IEND"),
+            }
+            pos += 12 + length;
+        }
+
+        let width = be_u32(&header[0..4]) as usize;
+        let height = be_u32(&header[4..8]) as usize;
+        let color_type = header[9];
+        let channels = match color_type {
+            0 => 1,
+            2 => 3,
+            _ => 4,
+        };
+
+        assert_eq!(u16::from_be_bytes([idat[0], idat[1]]) % 31, 0);
+        let rows = inflate(&idat[2..idat.len() - 4]);
+        let adler = be_u32(&idat[idat.len() - 4..]);
+        assert_eq!(adler, adler32(&rows));
+
+        let row_size = width * channels;
+        let mut samples = vec![0u8; row_size * height];
+        for y in 0..height {
+            let filter_type = rows[y * (row_size + 1)];
+            for i in 0..row_size {
+                let left = if i >= channels {
+                    samples[y * row_size + i - channels]
+                } else {
+                    0
+                };
+                let up = if y > 0 {
+                    samples[(y - 1) * row_size + i]
+                } else {
+                    0
+                };
+                let up_left = if y > 0 && i >= channels {
+                    samples[(y - 1) * row_size + i - channels]
+                } else {
+                    0
+                };
+                let predicted = match filter_type {
+                    0 => 0,
+                    1 => left,
+                    2 => up,
+                    3 => ((left as u16 + up as u16) / 2) as u8,
+                    _ => paeth(left, up, up_left),
+                };
+                samples[y * row_size + i] =
+                    rows[y * (row_size + 1) + 1 + i].wrapping_add(predicted);
+            }
+        }
+
+        (width, height, color_type, samples)
+    }
+
+    // Repeating stripes with some noise, so rows get matched at several distances
+    fn test_image(width: usize, height: usize, format: PixelFormat) -> Image {
+        let data = (0..format.buffer_size(width, height))
+            .map(|i| ((i % 97) * 3 + (i * i) % 7) as u8)
+            .collect();
+
+        Image::from_vec(width, height, format, data).unwrap()
+    }
+
+    #[test]
+    fn computes_known_checksums() {
+        assert_eq!(
+            crc32_update(0xFFFF_FFFF, b"123456789") ^ 0xFFFF_FFFF,
+            0xCBF4_3926
+        );
+        assert_eq!(adler32(b"Wikipedia"), 0x11E6_0398);
+    }
+
+    #[test]
+    fn round_trips_stored_and_fixed_huffman_data() {
+        // the large image needs several stored blocks
+        let cases = [
+            (test_image(5, 3, PixelFormat::Gray8), 0),
+            (test_image(7, 4, PixelFormat::Rgb8), 2),
+            (test_image(6, 5, PixelFormat::Rgba8), 6),
+            (test_image(200, 120, PixelFormat::Rgb8), 2),
+        ];
+        for (img, color_type) in cases.iter() {
+            for compression in [Compression::Stored, Compression::Fast].iter() {
+                let mut bytes = Vec::new();
+                write_png(img, &mut bytes, *compression).unwrap();
+                let read_back = read_png(&bytes);
+                assert_eq!((read_back.0, read_back.1), (img.width(), img.height()));
+                assert_eq!(read_back.2, *color_type);
+                assert_eq!(read_back.3, img.as_bytes());
+            }
+        }
+    }
+}
@@ -0,0 +1,277 @@
+use crate::image::{Image, PixelFormat};
+use std::io;
+use std::io::{Read, Write};
+
+// Writes the image as a binary PPM (P6), grayscale images repeating their luma
+// on every channel
+pub fn write_ppm<W: Write>(img: &Image, writer: &mut W) -> io::Result<()> {
+    write!(writer, "P6\n{} {}\n255\n", img.width(), img.height())?;
+    write_samples(img, writer, 3)
+}
+
+// Writes the luma of the image as a binary PGM (P5)
+pub fn write_pgm<W: Write>(img: &Image, writer: &mut W) -> io::Result<()> {
+    write!(writer, "P5\n{} {}\n255\n", img.width(), img.height())?;
+    write_samples(img, writer, 1)
+}
+
+// Writes the image as a PAM (P7), with the GRAYSCALE, RGB or RGB_ALPHA tuple
+// type depending on its format
+pub fn write_pam<W: Write>(img: &Image, writer: &mut W) -> io::Result<()> {
+    let (depth, tuple_type) = match img.format() {
+        PixelFormat::Gray8 => (1, "GRAYSCALE"),
+        PixelFormat::Rgba8 | PixelFormat::Bgra8 => (4, "RGB_ALPHA"),
+        _ => (3, "RGB"),
+    };
+
+    write!(
+        writer,
+        "P7\nWIDTH {}\nHEIGHT {}\nDEPTH {}\nMAXVAL 255\nTUPLTYPE {}\nENDHDR\n",
+        img.width(),
+        img.height(),
+        depth,
+        tuple_type
+    )?;
+    write_samples(img, writer, depth)
+}
+
+// Writes every pixel as `depth` samples: luma, RGB, or RGB followed by alpha
+fn write_samples<W: Write>(img: &Image, writer: &mut W, depth: usize) -> io::Result<()> {
+    let mut row = Vec::with_capacity(img.width() * depth);
+    for y in 0..img.height() {
+        row.clear();
+        for x in 0..img.width() {
+            match depth {
+                1 => row.push(img.get_luma(x, y).unwrap_or_default()),
+                _ => row.extend_from_slice(&img.get_rgb(x, y).unwrap_or_default()),
+            }
+            if depth == 4 {
+                row.push(img.get_pixel(x, y).unwrap_or_default()[3]);
+            }
+        }
+        writer.write_all(&row)?;
+    }
+
+    Ok(())
+}
+
+// Reads a P2, P3, P5, P6 or P7 image into a Gray8, RGB8 or RGBA8 image.
+// Samples with a maximum value other than 255 are scaled to 8 bits.
+pub fn read<R: Read>(mut reader: R) -> io::Result<Image> {
+    let mut bytes = Vec::new();
+    reader.read_to_end(&mut bytes)?;
+    let mut header = Header {
+        bytes: &bytes,
+        pos: 0,
+    };
+
+    let magic = header.token()?;
+    let (width, height, format, max_value, binary) = match magic {
+        "P7" => {
+            let (width, height, format, max_value) = header.pam_fields()?;
+            (width, height, format, max_value, true)
+        }
+        "P2" | "P3" | "P5" | "P6" => {
+            let width = header.number()?;
+            let height = header.number()?;
+            let max_value = header.number()?;
+            let format = if magic == "P2" || magic == "P5" {
+                PixelFormat::Gray8
+            } else {
+                PixelFormat::Rgb8
+            };
+            (
+                width,
+                height,
+                format,
+                max_value,
+                magic == "P5" || magic == "P6",
+            )
+        }
+        _ => return Err(invalid_data("not a PNM image")),
+    };
+    if !(1..=255).contains(&max_value) {
+        return Err(invalid_data("only maximum values up to 255 are supported"));
+    }
+
+    let size = width
+        .checked_mul(height)
+        .and_then(|pixels| pixels.checked_mul(format.channels()))
+        .ok_or_else(|| invalid_data("image size overflows"))?;
+    let mut data = if binary {
+        // a single whitespace separates the header from the samples
+        let start = header.pos + 1;
+        bytes
+            .get(start..)
+            .and_then(|data| data.get(..size))
+            .ok_or_else(|| invalid_data("image data ended unexpectedly"))?
+            .to_vec()
+    } else {
+        (0..size)
+            .map(|_| header.number().map(|val| val.min(max_value) as u8))
+            .collect::<io::Result<Vec<_>>>()?
+    };
+    if max_value != 255 {
+        for val in data.iter_mut() {
+            *val = ((*val).min(max_value as u8) as usize * 255 / max_value) as u8;
+        }
+    }
+
+    Image::from_vec(width, height, format, data)
+        .ok_or_else(|| invalid_data("image data does not match its size"))
+}
+
+// Whitespace separated tokens of a header, skipping `#` comments
+struct Header<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Header<'a> {
+    fn token(&mut self) -> io::Result<&'a str> {
+        loop {
+            match self.bytes.get(self.pos) {
+                Some(b'#') => {
+                    while self.bytes.get(self.pos).is_some_and(|byte| *byte != b'\n') {
+                        self.pos += 1;
+                    }
+                }
+                Some(byte) if byte.is_ascii_whitespace() => self.pos += 1,
+                Some(_) => break,
+                None => return Err(invalid_data("header ended unexpectedly")),
+            }
+        }
+
+        let start = self.pos;
+        while self
+            .bytes
+            .get(self.pos)
+            .is_some_and(|byte| !byte.is_ascii_whitespace())
+        {
+            self.pos += 1;
+        }
+
+        std::str::from_utf8(&self.bytes[start..self.pos])
+            .map_err(|_| invalid_data("header is not ASCII"))
+    }
+
+    fn number(&mut self) -> io::Result<usize> {
+        self.token()?
+            .parse()
+            .map_err(|_| invalid_data("expected a number in the header"))
+    }
+
+    // Reads the `KEY value` lines of a PAM header up to ENDHDR
+    fn pam_fields(&mut self) -> io::Result<(usize, usize, PixelFormat, usize)> {
+        let (mut width, mut height, mut depth, mut max_value) = (None, None, None, None);
+        let mut tuple_type = None;
+        loop {
+            match self.token()? {
+                "WIDTH" => width = Some(self.number()?),
+                "HEIGHT" => height = Some(self.number()?),
+                "DEPTH" => depth = Some(self.number()?),
+                "MAXVAL" => max_value = Some(self.number()?),
+                "TUPLTYPE" => tuple_type = Some(self.token()?),
+                "ENDHDR" => break,
+                _ => return Err(invalid_data("unknown PAM header field")),
+            }
+        }
+
+        let format = match (tuple_type, depth) {
+            (Some("GRAYSCALE"), Some(1)) | (None, Some(1)) => PixelFormat::Gray8,
+            (Some("RGB"), Some(3)) | (None, Some(3)) => PixelFormat::Rgb8,
+            (Some("RGB_ALPHA"), Some(4)) | (None, Some(4)) => PixelFormat::Rgba8,
+            _ => return Err(invalid_data("unsupported PAM tuple type")),
+        };
+        match (width, height, max_value) {
+            (Some(width), Some(height), Some(max_value)) => Ok((width, height, format, max_value)),
+            _ => Err(invalid_data("PAM header misses a field")),
+        }
+    }
+}
+
+fn invalid_data(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_image(format: PixelFormat) -> Image {
+        let (width, height) = (5, 3);
+        let data = (0..format.buffer_size(width, height))
+            .map(|i| (i * 37 % 256) as u8)
+            .collect();
+
+        Image::from_vec(width, height, format, data).unwrap()
+    }
+
+    fn round_trip<F>(img: &Image, write: F) -> Image
+    where
+        F: Fn(&Image, &mut Vec<u8>) -> io::Result<()>,
+    {
+        let mut bytes = Vec::new();
+        write(img, &mut bytes).unwrap();
+        read(&bytes[..]).unwrap()
+    }
+
+    #[test]
+    fn round_trips_ppm_pgm_and_pam() {
+        let rgb = test_image(PixelFormat::Rgb8);
+        let gray = test_image(PixelFormat::Gray8);
+        let rgba = test_image(PixelFormat::Rgba8);
+
+        let cases = [
+            (&rgb, round_trip(&rgb, write_ppm)),
+            (&gray, round_trip(&gray, write_pgm)),
+            (&rgb, round_trip(&rgb, write_pam)),
+            (&gray, round_trip(&gray, write_pam)),
+            (&rgba, round_trip(&rgba, write_pam)),
+        ];
+        for (img, read_back) in cases.iter() {
+            assert_eq!(read_back.format(), img.format());
+            assert_eq!((read_back.width(), read_back.height()), (5, 3));
+            assert_eq!(read_back.as_bytes(), img.as_bytes());
+        }
+    }
+
+    #[test]
+    fn converts_other_formats_when_writing() {
+        let bgr = test_image(PixelFormat::Bgr8);
+        let read_back = round_trip(&bgr, write_ppm);
+        for (x, y) in [(0, 0), (4, 2), (2, 1)].iter() {
+            assert_eq!(read_back.get_rgb(*x, *y), bgr.get_rgb(*x, *y));
+        }
+
+        let read_back = round_trip(&bgr, write_pgm);
+        assert_eq!(read_back.get_luma(3, 1), bgr.get_luma(3, 1));
+    }
+
+    #[test]
+    fn scales_samples_below_255() {
+        let img = read(&b"P5\n3 1\n15\n\x00\x0f\x07"[..]).unwrap();
+        assert_eq!(img.as_bytes(), [0, 255, 119]);
+
+        let img = read(&b"P3\n# a comment\n1 1 3\n3 1 0"[..]).unwrap();
+        assert_eq!(img.as_bytes(), [255, 85, 0]);
+
+        let pam = b"P7\nWIDTH 2\nHEIGHT 1\nDEPTH 4\nMAXVAL 1\nTUPLTYPE RGB_ALPHA\nENDHDR\n\x01\x00\x01\x01\x00\x01\x00\x00";
+        let img = read(&pam[..]).unwrap();
+        assert_eq!(img.format(), PixelFormat::Rgba8);
+        assert_eq!(img.as_bytes(), [255, 0, 255, 255, 0, 255, 0, 0]);
+    }
+
+    #[test]
+    fn dumps_binary_files() {
+        let img = test_image(PixelFormat::Rgb8);
+        let path = std::env::temp_dir().join(format!("rpeg-dump-{}.ppm", std::process::id()));
+        let path = path.to_str().unwrap();
+
+        img.dump_to_ppm(path).unwrap();
+        let bytes = std::fs::read(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert!(bytes.starts_with(b"P6\n5 3\n255\n"));
+        assert_eq!(read(&bytes[..]).unwrap().as_bytes(), img.as_bytes());
+    }
+}
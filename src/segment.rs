@@ -0,0 +1,167 @@
+use crate::component::Component;
+use std::fmt;
+
+// A marker segment as found in the file, reported by `Decoder::inspect`
+pub enum Segment {
+    App0 {
+        length: u16,
+        version: (u8, u8),
+        density_unit: u8,
+        density: (u16, u16),
+        thumbnail: (u8, u8),
+    },
+    Dqt {
+        length: u16,
+        tables: Vec<(u8, u8, [u16; 64])>, // (precision, destination, table in zigzag order)
+    },
+    Dht {
+        length: u16,
+        tables: Vec<(u8, u8, usize)>, // (table type, table number, symbol count)
+    },
+    Sof {
+        length: u16,
+        marker: u8,
+        precision: u8,
+        height: usize,
+        width: usize,
+        components: Vec<Component>,
+    },
+    Sos {
+        length: u16,
+        components: Vec<(u8, u8, u8)>, // (component id, dc table, ac table)
+        spectral_selection: (u8, u8),
+        approximation: (u8, u8), // (high, low) bit positions
+    },
+    Dri {
+        length: u16,
+        interval: u16,
+    },
+    Comment {
+        length: u16,
+        text: String,
+    },
+    Unknown {
+        marker: u8,
+        length: Option<u16>,
+    },
+    EndOfImage,
+}
+
+impl fmt::Display for Segment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Segment::App0 {
+                length,
+                version,
+                density_unit,
+                density,
+                thumbnail,
+            } => {
+                writeln!(f, "Parsing APP-O segment:")?;
+                writeln!(f, "\tsegment length: {}", length)?;
+                writeln!(f, "\tversion: {}.{}", version.0, version.1)?;
+                writeln!(
+                    f,
+                    "\tdensity unit: {} (00 for no units, 01 for pixels per inch, 02 for pixels per cm)",
+                    density_unit
+                )?;
+                writeln!(f, "\tpixel density: {}x{}", density.0, density.1)?;
+                write!(f, "\tthumbnail size: {}x{}", thumbnail.0, thumbnail.1)
+            }
+            Segment::Dqt { length, tables } => {
+                writeln!(f, "Parsing DQT segment:")?;
+                write!(f, "\tsegment length: {}", length)?;
+                for (precision, destination, table) in tables.iter() {
+                    writeln!(f)?;
+                    writeln!(f, "\tprecision: {} (0 = 8-bit, 1 = 16-bit)", precision)?;
+                    writeln!(f, "\tdestination: {} (0 to 3)", destination)?;
+                    write!(f, "\tquantization table:")?;
+                    for row in table.chunks(8) {
+                        write!(f, "\n\t\t")?;
+                        for val in row.iter() {
+                            write!(f, "{} ", val)?;
+                        }
+                    }
+                }
+                Ok(())
+            }
+            Segment::Dht { length, tables } => {
+                writeln!(f, "Parsing DHT segment:")?;
+                write!(f, "\tsegment length: {}", length)?;
+                for (table_type, table_number, symbol_count) in tables.iter() {
+                    write!(f, "\n\ttable type: {} (0 for DC, 1 for AC)", table_type)?;
+                    write!(f, "\n\ttable number: {}", table_number)?;
+                    write!(f, "\n\tsymbols: {}", symbol_count)?;
+                }
+                Ok(())
+            }
+            Segment::Sof {
+                length,
+                marker,
+                precision,
+                height,
+                width,
+                components,
+            } => {
+                writeln!(f, "Parsing SOF{} segment:", marker & 0x0F)?;
+                writeln!(f, "\tsegment length: {}", length)?;
+                writeln!(f, "\tprecision: {}", precision)?;
+                writeln!(f, "\timage size: {}x{}", height, width)?;
+                write!(f, "\tcomponents (sampling, quantization table):")?;
+                for component in components.iter() {
+                    write!(
+                        f,
+                        "\n\t\t{} -> {}x{}, {}",
+                        component.id,
+                        component.horizontal_sampling,
+                        component.vertical_sampling,
+                        component.quantization_table
+                    )?;
+                }
+                Ok(())
+            }
+            Segment::Sos {
+                length,
+                components,
+                spectral_selection,
+                approximation,
+            } => {
+                writeln!(f, "Parsing SOS segment:")?;
+                writeln!(f, "\tsegment length: {}", length)?;
+                writeln!(
+                    f,
+                    "\tspectral selection: {}..={}",
+                    spectral_selection.0, spectral_selection.1
+                )?;
+                writeln!(
+                    f,
+                    "\tsuccessive approximation: {} -> {}",
+                    approximation.0, approximation.1
+                )?;
+                write!(f, "\thuffman tables for components:")?;
+                for (component_id, dc, ac) in components.iter() {
+                    write!(f, "\n\t\t{} -> {} (dc), {} (ac)", component_id, dc, ac)?;
+                }
+                Ok(())
+            }
+            Segment::Dri { length, interval } => {
+                writeln!(f, "Parsing DRI segment:")?;
+                writeln!(f, "\tsegment length: {}", length)?;
+                write!(f, "\trestart interval: {} MCUs", interval)
+            }
+            Segment::Comment { length, text } => {
+                writeln!(f, "Comment marker:")?;
+                writeln!(f, "\tsegment length: {}", length)?;
+                write!(f, "\tcomment: {}", text)
+            }
+            Segment::Unknown { marker, length } => {
+                write!(f, "Unknown segment ({:02X})", marker)?;
+                if let Some(length) = length {
+                    write!(f, "\n\tsegment length: {}", length)?;
+                }
+                Ok(())
+            }
+            Segment::EndOfImage => write!(f, "End of image"),
+        }
+    }
+}
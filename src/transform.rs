@@ -0,0 +1,571 @@
+use crate::codes_markers::*;
+use crate::coefficients::CoefficientOrder;
+use crate::component::Component;
+use crate::decoder::Decoder;
+use crate::encoder::Encoder;
+use crate::error::{DecodeError, DecodeErrorKind};
+use crate::mcu::{MCU, ZIGZAG};
+use crate::segment::Segment;
+
+// Lossless operations applied to the quantized coefficients, as done by jpegtran
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Transform {
+    Rotate90, // clockwise
+    Rotate180,
+    Rotate270,
+    FlipHorizontal,
+    FlipVertical,
+    Transpose,  // across the top-left to bottom-right diagonal
+    Transverse, // across the top-right to bottom-left diagonal
+}
+
+// Quantized blocks (in zigzag order) of a component, row by row
+struct BlockGrid {
+    width: usize, // in blocks
+    height: usize,
+    blocks: Vec<[i32; 64]>,
+}
+
+impl BlockGrid {
+    fn flip_horizontal(&self) -> Self {
+        let mut blocks = Vec::with_capacity(self.blocks.len());
+        for y in 0..self.height {
+            for x in (0..self.width).rev() {
+                blocks.push(map_block(
+                    &self.blocks[y * self.width + x],
+                    |v, u, coeff| ((v, u), if u % 2 == 1 { -coeff } else { coeff }),
+                ));
+            }
+        }
+
+        Self { blocks, ..*self }
+    }
+
+    fn flip_vertical(&self) -> Self {
+        let mut blocks = Vec::with_capacity(self.blocks.len());
+        for y in (0..self.height).rev() {
+            for x in 0..self.width {
+                blocks.push(map_block(
+                    &self.blocks[y * self.width + x],
+                    |v, u, coeff| ((v, u), if v % 2 == 1 { -coeff } else { coeff }),
+                ));
+            }
+        }
+
+        Self { blocks, ..*self }
+    }
+
+    fn transpose(&self) -> Self {
+        let mut blocks = Vec::with_capacity(self.blocks.len());
+        for x in 0..self.width {
+            for y in 0..self.height {
+                blocks.push(map_block(
+                    &self.blocks[y * self.width + x],
+                    |v, u, coeff| ((u, v), coeff),
+                ));
+            }
+        }
+
+        Self {
+            width: self.height,
+            height: self.width,
+            blocks,
+        }
+    }
+
+    // Keeps the `width` x `height` blocks starting at column `x` and row `y`
+    fn crop(&self, x: usize, y: usize, width: usize, height: usize) -> Self {
+        let blocks = (y..y + height)
+            .flat_map(|y| {
+                self.blocks[y * self.width + x..y * self.width + x + width]
+                    .iter()
+                    .copied()
+            })
+            .collect();
+
+        Self {
+            width,
+            height,
+            blocks,
+        }
+    }
+}
+
+// Moves every coefficient of a zigzag ordered block to the (row, column)
+// frequency returned by `f`, along with its new value
+fn map_block<F: Fn(usize, usize, i32) -> ((usize, usize), i32)>(
+    zz_block: &[i32; 64],
+    f: F,
+) -> [i32; 64] {
+    let mut result = [0; 64];
+    for (v, row) in ZIGZAG.iter().enumerate() {
+        for (u, zz_index) in row.iter().enumerate() {
+            let ((new_v, new_u), coeff) = f(v, u, zz_block[*zz_index]);
+            result[ZIGZAG[new_v][new_u]] = coeff;
+        }
+    }
+
+    result
+}
+
+fn transpose_table(table: &[u16; 64]) -> [u16; 64] {
+    let mut result = [0; 64];
+    for (v, row) in ZIGZAG.iter().enumerate() {
+        for (u, zz_index) in row.iter().enumerate() {
+            result[ZIGZAG[u][v]] = table[*zz_index];
+        }
+    }
+
+    result
+}
+
+// Quantized coefficients of a decoded frame, as one block grid per component
+struct Frame {
+    width: usize,
+    height: usize,
+    components: Vec<Component>,
+    quantization_tables: Vec<[u16; 64]>, // one per component, in zigzag order
+    grids: Vec<BlockGrid>,               // padded to whole MCUs
+    restart_interval: u16,
+    segments: Vec<(u8, Vec<u8>)>, // APPn (other than the JFIF one) and COM segments
+}
+
+impl Frame {
+    fn decode(jpeg: &[u8]) -> Result<Self, DecodeError> {
+        let mut decoder = Decoder::from_bytes(jpeg);
+        let coefficients = decoder.decode_coefficients(CoefficientOrder::Zigzag)?;
+        let restart_interval = decoder.segments().iter().find_map(|segment| match segment {
+            Segment::Dri { interval, .. } => Some(*interval),
+            _ => None,
+        });
+
+        let mut frame = Self {
+            width: coefficients.width,
+            height: coefficients.height,
+            components: Vec::new(),
+            quantization_tables: Vec::new(),
+            grids: Vec::new(),
+            restart_interval: restart_interval.unwrap_or(0),
+            segments: metadata_segments(jpeg),
+        };
+        for component in coefficients.components.into_iter() {
+            frame.components.push(component.component);
+            frame.quantization_tables.push(component.quantization_table);
+            frame.grids.push(BlockGrid {
+                width: component.width_in_blocks,
+                height: component.height_in_blocks,
+                blocks: component.blocks,
+            });
+        }
+
+        Ok(frame)
+    }
+
+    fn mcu_size(&self) -> (usize, usize) {
+        let h_max = self.components.iter().map(|c| c.horizontal_sampling).max();
+        let v_max = self.components.iter().map(|c| c.vertical_sampling).max();
+
+        (8 * h_max.unwrap_or(1), 8 * v_max.unwrap_or(1))
+    }
+
+    fn mcu_grid_size(&self) -> (usize, usize) {
+        let (mcu_width, mcu_height) = self.mcu_size();
+
+        (
+            self.width.div_ceil(mcu_width),
+            self.height.div_ceil(mcu_height),
+        )
+    }
+
+    // Keeps the `width` x `height` pixels starting at the MCU in column `mcu_x`
+    // and row `mcu_y`
+    fn crop(&mut self, mcu_x: usize, mcu_y: usize, width: usize, height: usize) {
+        self.width = width;
+        self.height = height;
+        let (mcus_x, mcus_y) = self.mcu_grid_size();
+
+        for (grid, c) in self.grids.iter_mut().zip(self.components.iter()) {
+            *grid = grid.crop(
+                mcu_x * c.horizontal_sampling,
+                mcu_y * c.vertical_sampling,
+                mcus_x * c.horizontal_sampling,
+                mcus_y * c.vertical_sampling,
+            );
+        }
+    }
+
+    fn transpose(&mut self) {
+        for grid in self.grids.iter_mut() {
+            *grid = grid.transpose();
+        }
+        for c in self.components.iter_mut() {
+            std::mem::swap(&mut c.horizontal_sampling, &mut c.vertical_sampling);
+        }
+        for table in self.quantization_tables.iter_mut() {
+            *table = transpose_table(table);
+        }
+        std::mem::swap(&mut self.width, &mut self.height);
+    }
+
+    // Writes the frame as a baseline file with optimized Huffman tables, which
+    // fit any coefficient, the DC differences being recomputed from the new
+    // block order. The metadata and the restart interval are kept, but the
+    // progressive scans of the source become a single sequential one.
+    fn encode(&self, offset: usize) -> Result<Vec<u8>, DecodeError> {
+        let mcus = self.mcus().map_err(|kind| DecodeError::new(kind, offset))?;
+
+        let mut encoder = Encoder::new(100);
+        encoder.set_optimize_huffman(true);
+        encoder.set_restart_interval(self.restart_interval);
+        for (marker, contents) in self.segments.iter() {
+            encoder.add_segment(*marker, contents)?;
+        }
+        let mut bytes = Vec::new();
+        encoder.encode_mcus(
+            &mut bytes,
+            self.width,
+            self.height,
+            &self.components,
+            &self.quantization_tables,
+            &mcus,
+        )?;
+
+        Ok(bytes)
+    }
+
+    // Groups the blocks of every component back into MCUs
+    fn mcus(&self) -> Result<Vec<MCU>, DecodeErrorKind> {
+        let (mcus_x, mcus_y) = self.mcu_grid_size();
+        let sampling = self
+            .components
+            .iter()
+            .map(|c| (c.horizontal_sampling, c.vertical_sampling))
+            .collect::<Vec<_>>();
+
+        let mut mcus = Vec::with_capacity(mcus_x * mcus_y);
+        for mcu_id in 0..mcus_x * mcus_y {
+            let (mcu_x, mcu_y) = (mcu_id % mcus_x, mcu_id / mcus_x);
+            let zz_dct_coeff = self
+                .components
+                .iter()
+                .zip(self.grids.iter())
+                .map(|(c, grid)| {
+                    let mut blocks = Vec::with_capacity(c.blocks_per_mcu());
+                    for v in 0..c.vertical_sampling {
+                        for h in 0..c.horizontal_sampling {
+                            let x = mcu_x * c.horizontal_sampling + h;
+                            let y = mcu_y * c.vertical_sampling + v;
+                            blocks.push(grid.blocks[y * grid.width + x]);
+                        }
+                    }
+                    blocks
+                })
+                .collect();
+            mcus.push(MCU::new(mcu_id, zz_dct_coeff, sampling.clone())?);
+        }
+
+        Ok(mcus)
+    }
+}
+
+// Contents of the APPn (other than the JFIF APP0) and COM segments found before
+// the first scan, which carry the EXIF, XMP and ICC metadata
+fn metadata_segments(jpeg: &[u8]) -> Vec<(u8, Vec<u8>)> {
+    let mut segments = Vec::new();
+    let mut pos = 2; // after the SOI marker
+    while pos + 1 < jpeg.len() && jpeg[pos] == JFIF_BYTE_FF {
+        let marker = jpeg[pos + 1];
+        match marker {
+            JFIF_BYTE_FF => pos += 1, // fill byte
+            JFIF_SOS | JFIF_EOI => break,
+            0x01 | JFIF_RST0..=JFIF_RST7 => pos += 2,
+            _ => {
+                if pos + 4 > jpeg.len() {
+                    break;
+                }
+                let end = pos + 2 + u16::from_be_bytes([jpeg[pos + 2], jpeg[pos + 3]]) as usize;
+                if end > jpeg.len() || end < pos + 4 {
+                    break;
+                }
+                if matches!(marker, 0xE1..=0xEF | JFIF_COM) {
+                    segments.push((marker, jpeg[pos + 4..end].to_vec()));
+                }
+                pos = end;
+            }
+        }
+    }
+
+    segments
+}
+
+// Decodes `jpeg` up to its quantized coefficients and writes it back with
+// `transform` applied, keeping its quantization tables. Flipping the partial
+// MCUs found on the right and bottom edges would move their padding inside the
+// image, so they are dropped when `trim` is set and rejected otherwise.
+pub fn transform(jpeg: &[u8], transform: Transform, trim: bool) -> Result<Vec<u8>, DecodeError> {
+    let mut frame = Frame::decode(jpeg)?;
+    let error = |kind| DecodeError::new(kind, jpeg.len());
+
+    // edges of the source image that end up flipped
+    let (flip_x, flip_y) = match transform {
+        Transform::Rotate90 => (false, true),
+        Transform::Rotate180 => (true, true),
+        Transform::Rotate270 => (true, false),
+        Transform::FlipHorizontal => (true, false),
+        Transform::FlipVertical => (false, true),
+        Transform::Transpose => (false, false),
+        Transform::Transverse => (true, true),
+    };
+    let (mcu_width, mcu_height) = frame.mcu_size();
+    let (mut width, mut height) = (frame.width, frame.height);
+    if flip_x {
+        width -= width % mcu_width;
+    }
+    if flip_y {
+        height -= height % mcu_height;
+    }
+    if (width, height) != (frame.width, frame.height) {
+        if !trim {
+            return Err(error(DecodeErrorKind::Unsupported(
+                "flipping partial edge MCUs without trimming them",
+            )));
+        }
+        if width == 0 || height == 0 {
+            return Err(error(DecodeErrorKind::Unsupported(
+                "trimming an image smaller than one MCU",
+            )));
+        }
+        frame.crop(0, 0, width, height);
+    }
+
+    if matches!(
+        transform,
+        Transform::Rotate90 | Transform::Rotate270 | Transform::Transpose | Transform::Transverse
+    ) {
+        frame.transpose();
+    }
+    for grid in frame.grids.iter_mut() {
+        *grid = match transform {
+            Transform::Rotate90 | Transform::FlipHorizontal => grid.flip_horizontal(),
+            Transform::Rotate180 | Transform::Transverse => grid.flip_horizontal().flip_vertical(),
+            Transform::Rotate270 | Transform::FlipVertical => grid.flip_vertical(),
+            Transform::Transpose => continue,
+        };
+    }
+
+    frame.encode(jpeg.len())
+}
+
+// Decodes `jpeg` up to its quantized coefficients and writes back the
+// `width` x `height` region at (`x`, `y`), keeping its quantization tables.
+// Blocks cannot be split, so the top-left corner is moved up and left to the
+// MCU containing it, while the bottom-right corner stays where it was asked.
+pub fn crop(
+    jpeg: &[u8],
+    x: usize,
+    y: usize,
+    width: usize,
+    height: usize,
+) -> Result<Vec<u8>, DecodeError> {
+    let mut frame = Frame::decode(jpeg)?;
+
+    let right = x.saturating_add(width).min(frame.width);
+    let bottom = y.saturating_add(height).min(frame.height);
+    if width == 0 || height == 0 || right <= x || bottom <= y {
+        return Err(DecodeError::new(DecodeErrorKind::InvalidRegion, jpeg.len()));
+    }
+
+    let (mcu_width, mcu_height) = frame.mcu_size();
+    let (mcu_x, mcu_y) = (x / mcu_width, y / mcu_height);
+    frame.crop(
+        mcu_x,
+        mcu_y,
+        right - mcu_x * mcu_width,
+        bottom - mcu_y * mcu_height,
+    );
+
+    frame.encode(jpeg.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::{Image, PixelFormat};
+
+    const TRANSFORMS: [Transform; 7] = [
+        Transform::Rotate90,
+        Transform::Rotate180,
+        Transform::Rotate270,
+        Transform::FlipHorizontal,
+        Transform::FlipVertical,
+        Transform::Transpose,
+        Transform::Transverse,
+    ];
+
+    // A textured image with restart markers every 2 MCUs, an APP1 and a comment
+    fn test_jpeg(width: usize, height: usize, chroma_subsampling: bool) -> Vec<u8> {
+        let data = (0..width * height)
+            .flat_map(|i| {
+                let (x, y) = (i % width, i / width);
+                [(x * 5) as u8, (y * 7) as u8, ((x * y) % 256) as u8]
+            })
+            .collect::<Vec<_>>();
+        let img = Image::from_vec(width, height, PixelFormat::Rgb8, data).unwrap();
+
+        let mut encoder = Encoder::new(90);
+        encoder.set_chroma_subsampling(chroma_subsampling);
+        encoder.set_restart_interval(2);
+        encoder.add_segment(0xE1, b"Exif\0\0test").unwrap();
+        encoder.add_segment(JFIF_COM, b"a comment").unwrap();
+
+        encoder.encode(&img).unwrap()
+    }
+
+    fn decode(jpeg: &[u8]) -> Image {
+        Decoder::from_bytes(jpeg).decode().unwrap()
+    }
+
+    // Applies `transform` to the pixels of `img`
+    fn transform_pixels(img: &Image, transform: Transform) -> Image {
+        let (w, h) = (img.width(), img.height());
+        let (new_w, new_h) = match transform {
+            Transform::Rotate180 | Transform::FlipHorizontal | Transform::FlipVertical => (w, h),
+            _ => (h, w),
+        };
+        let mut data = Vec::with_capacity(new_w * new_h * 3);
+        for y in 0..new_h {
+            for x in 0..new_w {
+                let (src_x, src_y) = match transform {
+                    Transform::Rotate90 => (y, h - 1 - x),
+                    Transform::Rotate180 => (w - 1 - x, h - 1 - y),
+                    Transform::Rotate270 => (w - 1 - y, x),
+                    Transform::FlipHorizontal => (w - 1 - x, y),
+                    Transform::FlipVertical => (x, h - 1 - y),
+                    Transform::Transpose => (y, x),
+                    Transform::Transverse => (w - 1 - y, h - 1 - x),
+                };
+                data.extend_from_slice(&img.get_rgb(src_x, src_y).unwrap());
+            }
+        }
+
+        Image::from_vec(new_w, new_h, PixelFormat::Rgb8, data).unwrap()
+    }
+
+    // Largest difference between the samples of two images of the same size
+    fn max_difference(a: &Image, b: &Image) -> u8 {
+        assert_eq!((a.width(), a.height()), (b.width(), b.height()));
+        a.as_bytes()
+            .iter()
+            .zip(b.as_bytes().iter())
+            .map(|(a, b)| a.abs_diff(*b))
+            .max()
+            .unwrap()
+    }
+
+    // The bytes of the first segment with `marker`
+    fn segment(jpeg: &[u8], marker: u8) -> &[u8] {
+        let pos = jpeg
+            .windows(2)
+            .position(|m| m == [JFIF_BYTE_FF, marker])
+            .unwrap();
+        let length = u16::from_be_bytes([jpeg[pos + 2], jpeg[pos + 3]]) as usize;
+
+        &jpeg[pos..pos + 2 + length]
+    }
+
+    #[test]
+    fn transforms_match_the_decoded_pixels() {
+        let jpeg = test_jpeg(48, 32, true);
+        let img = decode(&jpeg);
+
+        for transform in TRANSFORMS.iter() {
+            let output = super::transform(&jpeg, *transform, false).unwrap();
+            let expected = transform_pixels(&img, *transform);
+            assert!(
+                max_difference(&decode(&output), &expected) <= 1,
+                "{:?}",
+                transform
+            );
+        }
+    }
+
+    #[test]
+    fn keeps_quantization_tables_and_metadata() {
+        let jpeg = test_jpeg(48, 32, true);
+        let mut decoder = Decoder::from_bytes(&jpeg);
+        decoder.inspect().unwrap();
+        let tables = decoder.quantization_tables();
+
+        for transform in TRANSFORMS.iter() {
+            let output = super::transform(&jpeg, *transform, false).unwrap();
+            let mut decoder = Decoder::from_bytes(&output);
+            decoder.inspect().unwrap();
+            if matches!(
+                transform,
+                Transform::Rotate180 | Transform::FlipHorizontal | Transform::FlipVertical
+            ) {
+                assert_eq!(segment(&output, JFIF_DQT), segment(&jpeg, JFIF_DQT));
+            } else {
+                let transposed = tables.iter().map(transpose_table).collect::<Vec<_>>();
+                assert_eq!(decoder.quantization_tables(), transposed);
+            }
+
+            assert_eq!(segment(&output, 0xE1), segment(&jpeg, 0xE1));
+            assert_eq!(segment(&output, JFIF_COM), segment(&jpeg, JFIF_COM));
+            assert_eq!(segment(&output, JFIF_DRI), segment(&jpeg, JFIF_DRI));
+        }
+    }
+
+    #[test]
+    fn trims_or_rejects_partial_edge_mcus() {
+        // 4:2:0 MCUs are 16x16, leaving a partial column of them
+        let jpeg = test_jpeg(40, 32, true);
+        let img = decode(&jpeg);
+
+        let err = super::transform(&jpeg, Transform::FlipHorizontal, false)
+            .err()
+            .unwrap();
+        assert!(matches!(err.kind(), DecodeErrorKind::Unsupported(_)));
+
+        let output = super::transform(&jpeg, Transform::FlipHorizontal, true).unwrap();
+        let expected = transform_pixels(&img.crop(0, 0, 32, 32), Transform::FlipHorizontal);
+        assert!(max_difference(&decode(&output), &expected) <= 1);
+
+        // the right edge stays in place, so nothing needs trimming
+        let output = super::transform(&jpeg, Transform::FlipVertical, false).unwrap();
+        let expected = transform_pixels(&img, Transform::FlipVertical);
+        assert!(max_difference(&decode(&output), &expected) <= 1);
+    }
+
+    #[test]
+    fn crops_at_mcu_offsets() {
+        for chroma_subsampling in [false, true].iter() {
+            let jpeg = test_jpeg(48, 40, *chroma_subsampling);
+            let img = decode(&jpeg);
+
+            // 8x8 MCUs without subsampling and 16x16 ones with it, the window
+            // starting past the first restart interval
+            let output = crop(&jpeg, 32, 16, 13, 20).unwrap();
+            let expected = img.crop(32, 16, 13, 20);
+            assert_eq!(decode(&output).as_bytes(), expected.as_bytes());
+            assert_eq!(segment(&output, JFIF_DRI), segment(&jpeg, JFIF_DRI));
+        }
+    }
+
+    #[test]
+    fn moves_the_corner_to_the_enclosing_mcu() {
+        let jpeg = test_jpeg(48, 40, true);
+        let img = decode(&jpeg);
+
+        // the corner goes up and left to (16, 0), the bottom-right one stays at (40, 22)
+        let output = decode(&crop(&jpeg, 20, 10, 20, 12).unwrap());
+        assert_eq!((output.width(), output.height()), (24, 22));
+        assert_eq!(output.as_bytes(), img.crop(16, 0, 24, 22).as_bytes());
+
+        // windows past the edges are clipped, empty ones rejected
+        let output = decode(&crop(&jpeg, 32, 32, 100, 100).unwrap());
+        assert_eq!(output.as_bytes(), img.crop(32, 32, 16, 8).as_bytes());
+        let err = crop(&jpeg, 48, 0, 8, 8).err().unwrap();
+        assert!(matches!(err.kind(), DecodeErrorKind::InvalidRegion));
+    }
+}